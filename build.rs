@@ -1,45 +1,233 @@
-use std::path::Path;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 const ENZYME_VER: &str = "0.0.29";
 const RUSTC_VER: &str = "1.59.0";
 const LLVM_VER: &str = "13";
 
-fn choose_library() {
-    let platform = std::env::var("TARGET").unwrap();
-    let enzyme_basedir = dirs::cache_dir().unwrap().join("enzyme");
-    let enzyme_path = enzyme_basedir
-        .join("Enzyme-".to_owned() + ENZYME_VER)
+const ENZYME_LIB_DIR_ENV: &str = "ENZYME_LIB_DIR";
+const LLVM_PREFIX_ENV: &str = "LLVM_SYS_130_PREFIX";
+const LLVM_CONFIG_ENV: &str = "LLVM_CONFIG_PATH";
+
+/// A directory which we found, and how we found it.
+struct Found {
+    dir: PathBuf,
+    source: String,
+}
+
+/// Where the enzyme_build script places everything.
+fn cache_basedir() -> PathBuf {
+    dirs::cache_dir()
+        .expect("Enzyme needs access to your cache dir.")
         .join("enzyme")
-        .join("build")
-        .join("Enzyme");
-    let llvm_path = enzyme_basedir
+}
+
+fn cache_llvm_dir() -> PathBuf {
+    let platform = env::var("TARGET").unwrap();
+    cache_basedir()
         .join("rustc-".to_owned() + RUSTC_VER + "-src")
         .join("build")
-        .join(&platform)
+        .join(platform)
         .join("llvm")
-        .join("lib");
-    let enzyme_lib = "Enzyme-".to_owned() + LLVM_VER;
-    let llvm_lib = "LLVM-".to_owned() + LLVM_VER + "-rust-" + RUSTC_VER + "-nightly";
+}
+
+/// Install prefixes have lib/, build dirs keep it in build/.
+fn first_existing(prefix: &Path, sub_dir: &str) -> PathBuf {
+    let installed = prefix.join(sub_dir);
+    let build = prefix.join("build").join(sub_dir);
+    if !installed.exists() && build.exists() {
+        build
+    } else {
+        installed
+    }
+}
+
+fn enzyme_lib_dir() -> Found {
+    if let Ok(dir) = env::var(ENZYME_LIB_DIR_ENV) {
+        return Found {
+            dir: PathBuf::from(dir),
+            source: ENZYME_LIB_DIR_ENV.to_owned(),
+        };
+    }
+    let dir = cache_basedir()
+        .join("Enzyme-".to_owned() + ENZYME_VER)
+        .join("enzyme")
+        .join("build")
+        .join("Enzyme");
+    Found {
+        dir,
+        source: "the enzyme cache".to_owned(),
+    }
+}
+
+/// Run llvm-config with the given argument, None if that fails.
+fn query(llvm_config: &Path, arg: &str) -> Option<String> {
+    let output = Command::new(llvm_config).arg(arg).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+fn is_our_version(version: &str) -> bool {
+    version.split('.').next() == Some(LLVM_VER)
+}
+
+/// Ask llvm-config, if we have one for our LLVM version.
+fn from_llvm_config() -> Option<Found> {
+    let candidates = match env::var(LLVM_CONFIG_ENV) {
+        Ok(path) => vec![path],
+        Err(_) => vec![
+            "llvm-config-".to_owned() + LLVM_VER,
+            "llvm-config".to_owned(),
+        ],
+    };
+    for llvm_config in candidates {
+        let llvm_config = Path::new(&llvm_config);
+        let version = match query(llvm_config, "--version") {
+            Some(version) => version,
+            None => continue,
+        };
+        if !is_our_version(&version) {
+            println!(
+                "Ignoring {}, it has version {} instead of {}",
+                llvm_config.display(),
+                version,
+                LLVM_VER
+            );
+            continue;
+        }
+        return Some(Found {
+            dir: PathBuf::from(query(llvm_config, "--libdir")?),
+            source: format!("{} {}", llvm_config.display(), version),
+        });
+    }
+    None
+}
+
+/// The first dir in PATH with an llvm-config of our LLVM version, its libs are next to it.
+/// The other versions which we found are added to `rejected`.
+fn from_path(rejected: &mut Vec<String>) -> Option<Found> {
+    let path = env::var_os("PATH")?;
+    for bin_dir in env::split_paths(&path) {
+        let llvm_config = bin_dir.join("llvm-config");
+        let version = match query(&llvm_config, "--version") {
+            Some(version) => version,
+            None => continue,
+        };
+        if !is_our_version(&version) {
+            rejected.push(format!("{} {}", llvm_config.display(), version));
+            continue;
+        }
+        return Some(Found {
+            dir: PathBuf::from(query(&llvm_config, "--libdir")?),
+            source: format!("{} {} (from PATH)", llvm_config.display(), version),
+        });
+    }
+    None
+}
+
+fn llvm_lib_dir() -> Found {
+    if let Ok(prefix) = env::var(LLVM_PREFIX_ENV) {
+        return Found {
+            dir: first_existing(Path::new(&prefix), "lib"),
+            source: LLVM_PREFIX_ENV.to_owned(),
+        };
+    }
+    if let Some(found) = from_llvm_config() {
+        return found;
+    }
+    let mut rejected = vec![];
+    if let Some(found) = from_path(&mut rejected) {
+        return found;
+    }
+    let dir = cache_llvm_dir().join("lib");
+    if !dir.exists() && !rejected.is_empty() {
+        panic!(
+            "We need LLVM {}, but only found {} in PATH. Please set {}.",
+            LLVM_VER,
+            rejected.join(", "),
+            LLVM_PREFIX_ENV
+        );
+    }
+    Found {
+        dir,
+        source: "the enzyme cache".to_owned(),
+    }
+}
+
+/// Rust's LLVM calls its library LLVM-13-rust-<version>-nightly, distributions just LLVM-13.
+fn llvm_lib_name(lib_dir: &Path) -> String {
+    let versioned = "libLLVM-".to_owned() + LLVM_VER;
+    let mut names: Vec<String> = std::fs::read_dir(lib_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.starts_with(&versioned))
+        .filter_map(|name| {
+            let name = name
+                .strip_suffix(".so")
+                .or_else(|| name.strip_suffix(".dylib"))?;
+            Some(name.strip_prefix("lib")?.to_owned())
+        })
+        .collect();
+    names.sort_by_key(|name| name.len());
+    names.into_iter().next().unwrap_or_else(|| {
+        panic!(
+            "Couldn't find {}*.so or {}*.dylib in {}. Please set {}.",
+            versioned,
+            versioned,
+            lib_dir.display(),
+            LLVM_PREFIX_ENV
+        )
+    })
+}
+
+fn choose_library() {
+    println!("cargo:rerun-if-env-changed={}", ENZYME_LIB_DIR_ENV);
+    println!("cargo:rerun-if-env-changed={}", LLVM_PREFIX_ENV);
+    println!("cargo:rerun-if-env-changed={}", LLVM_CONFIG_ENV);
+    println!("cargo:rerun-if-env-changed=PATH");
+
+    let enzyme = enzyme_lib_dir();
+    let llvm_lib = llvm_lib_dir();
+    println!(
+        "Enzyme libs: {} (from {})",
+        enzyme.dir.display(),
+        enzyme.source
+    );
+    println!(
+        "LLVM libs: {} (from {})",
+        llvm_lib.dir.display(),
+        llvm_lib.source
+    );
     assert!(
-        enzyme_path.exists(),
-        "enzyme dir couldn't be found: {}",
-        enzyme_path.display()
+        enzyme.dir.exists(),
+        "enzyme dir couldn't be found: {} (from {}). Please set {}.",
+        enzyme.dir.display(),
+        enzyme.source,
+        ENZYME_LIB_DIR_ENV
     );
     assert!(
-        llvm_path.exists(),
-        "llvm dir couldn't be found: {}",
-        llvm_path.display()
+        llvm_lib.dir.exists(),
+        "llvm dir couldn't be found: {} (from {}). Please set {}.",
+        llvm_lib.dir.display(),
+        llvm_lib.source,
+        LLVM_PREFIX_ENV
     );
-    println!("cargo:rustc-link-search={}", enzyme_path.display());
-    println!("cargo:rustc-link-search={}", llvm_path.display());
-    println!("cargo:rustc-link-lib=dylib={}", llvm_lib);
+
+    let enzyme_lib = "Enzyme-".to_owned() + LLVM_VER;
+    let llvm_lib_name = llvm_lib_name(&llvm_lib.dir);
+    println!("cargo:rustc-link-search={}", enzyme.dir.display());
+    println!("cargo:rustc-link-search={}", llvm_lib.dir.display());
+    println!("cargo:rustc-link-lib=dylib={}", llvm_lib_name);
     println!("cargo:rustc-link-lib=dylib={}", enzyme_lib);
 }
 
 fn copy_bindings() {
-    let cache_dir = dirs::cache_dir().expect("Enzyme needs access to your cache dir.");
-    let src = cache_dir.join("enzyme").join("enzyme.rs");
-    let dst = Path::new(&std::env::var("OUT_DIR").unwrap()).join("enzyme.rs");
+    let src = cache_basedir().join("enzyme.rs");
+    let dst = Path::new(&env::var("OUT_DIR").unwrap()).join("enzyme.rs");
     if !src.exists() {
         panic!("please first generate the bindings");
     }
@@ -49,10 +237,8 @@ fn copy_bindings() {
 
 fn main() {
     println!("cargo:rustc-env=RUSTC_VER={}", RUSTC_VER);
-    println!(
-        "cargo:rustc-env=TARGET={}",
-        std::env::var("TARGET").unwrap()
-    );
+    println!("cargo:rustc-env=ENZYME_VER={}", ENZYME_VER);
+    println!("cargo:rustc-env=LLVM_VER={}", LLVM_VER);
     copy_bindings();
     choose_library();
 }
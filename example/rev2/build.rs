@@ -1,17 +1,11 @@
 #![allow(unused_variables)]
 use oxide_enzyme::{FncInfo, ReturnActivity, CDIFFE_TYPE};
-use std::env;
-use std::path::PathBuf;
 
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=src/main.rs");
     println!("cargo:rerun-if-changed=../src/lib.rs");
 
-    let entry_path = PathBuf::from(env::var("OUT_DIR").unwrap());
-    let check_path = entry_path.join("enzyme-done");
-    println!("cargo:rerun-if-changed={}", check_path.display());
-
     let d_test = FncInfo::new(
         "test",
         "d_test",
@@ -30,5 +24,13 @@ fn main() {
         //ReturnActivity::Gradient, // returns {f64}
     );
 
-    oxide_enzyme::build(vec![d_test, d_test_ref]);
+    // Functions of other crates are given by their path.
+    let d_g = FncInfo::new(
+        "simple_dep::g",
+        "d_g",
+        vec![CDIFFE_TYPE::DFT_OUT_DIFF],
+        ReturnActivity::Gradient, // returns {f64}
+    );
+
+    oxide_enzyme::build(vec![d_test, d_test_ref, d_g]);
 }
@@ -36,6 +36,11 @@ fn g_wrap(x: f64) -> f64 {
     g(x)
 }
 
+// Generated from simple_dep::g, see build.rs
+extern "C" {
+    fn d_g(x: f64) -> f64;
+}
+
 #[no_mangle]
 fn h(x: f64, y: f64) -> f64 {
     2.0 * x + y
@@ -56,6 +61,7 @@ fn main() {
         println!("{} {}", X2, D_X2);
         dbg!(d_test_ref(&mut X2, &mut D_X2));
         println!("{} {}", X2, D_X2);
+        dbg!(g(1.0), d_g(1.0));
         //dbg!(&f_wrap(1.0, 1.0));
         //dbg!(&g_wrap(1.0));
         //dbg!(&enzyme3(1.0, 1.0));
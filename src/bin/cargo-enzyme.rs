@@ -0,0 +1,31 @@
+//! `cargo enzyme`, compiles a crate which uses oxide-enzyme in its build.rs.
+//!
+//! The build script compiles the crate to LLVM bitcode on its own, so a single cargo call
+//! with the enzyme toolchain is enough. All arguments are passed through to cargo,
+//! arguments after `--` are passed to rustc.
+//!
+//! ```bash
+//! cargo enzyme --release --features foo --target x86_64-unknown-linux-gnu -p my_crate
+//! ```
+use std::env;
+use std::process::{exit, Command};
+
+const TOOLCHAIN: &str = "+enzyme";
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // cargo calls us as `cargo-enzyme enzyme <args>`
+    if args.first().map(|x| x.as_str()) == Some("enzyme") {
+        args.remove(0);
+    }
+
+    // Only the rustup proxy understands the toolchain selection, so we don't use $CARGO.
+    let mut cargo = Command::new("cargo");
+    cargo.args([TOOLCHAIN, "rustc"]);
+    cargo.args(&args);
+
+    let status = cargo.status().expect("Failed to run cargo.");
+    if !status.success() {
+        exit(status.code().unwrap_or(1));
+    }
+}
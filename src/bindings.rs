@@ -0,0 +1,227 @@
+//! Generate the Rust declarations of the generated functions, so users don't have to
+//! write extern blocks matching our types by hand.
+//!
+//! The crate includes them through `include!(concat!(env!("OUT_DIR"), "/gradients.rs"))`.
+//! Its bitcode pass can't know them yet, so it reuses the file of the previous build. On the
+//! first build there is none, so we set the `enzyme_bootstrap` cfg instead, which allows
+//! users to compile their calls out.
+use crate::enzyme::CDIFFE_TYPE;
+use crate::wrappers::get_params;
+use crate::{dwarf, env_var, get_type, EnzymeBuildError, FncInfo};
+use llvm_sys::core::*;
+use llvm_sys::prelude::*;
+use llvm_sys::LLVMTypeKind;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+pub const GRADIENTS_FILE: &str = "gradients.rs";
+/// Tells the bitcode pass where the declarations of the last build are.
+pub const GRADIENTS_ENV: &str = "ENZYME_GRADIENTS_RS";
+pub const BOOTSTRAP_CFG: &str = "enzyme_bootstrap";
+
+pub const INTEGER_TYPES: &[&str] = &[
+    "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128", "isize", "bool",
+    "char",
+];
+
+const HEADER: &str = "// Generated by oxide-enzyme from the FncInfos in your build.rs, don't edit it.\n\
+                      // Include it with include!(concat!(env!(\"OUT_DIR\"), \"/gradients.rs\"));\n";
+
+/// The Rust declarations of all `grad_fncs`, except for the `intermediate` ones.
+/// `primals` holds the primal function (null for generated ones) and FncInfo of each.
+/// `tape_sizes` holds the tape size of each reverse pass of split mode.
+pub fn declarations(
+    grad_fncs: &[LLVMValueRef],
+    names: &[String],
+    primals: &[(LLVMValueRef, FncInfo)],
+    intermediate: &[String],
+    tape_sizes: &[(String, u64)],
+) -> String {
+    let mut bindings = Bindings {
+        structs: String::new(),
+        fncs: String::new(),
+    };
+    for ((&fnc, name), (primal, info)) in grad_fncs.iter().zip(names).zip(primals) {
+        if intermediate.contains(name) {
+            continue;
+        }
+        let param_names = if primal.is_null() {
+            vec![]
+        } else {
+            unsafe { primal_param_names(*primal, info) }
+        };
+        // The other functions are still usable, so we don't fail the whole build.
+        if let Err(ty) = unsafe { bindings.declare_function(fnc, name, &param_names) } {
+            let e = EnzymeBuildError::UnsupportedType {
+                function: name.clone(),
+                ty,
+            };
+            println!("cargo:warning={} Please declare it yourself.", e);
+        }
+    }
+    let mut consts = String::new();
+    for (name, size) in tape_sizes {
+        if !intermediate.contains(name) {
+            consts += &format!(
+                "#[allow(non_upper_case_globals)]\npub const ENZYME_TAPE_SIZE_{}: usize = {};\n",
+                name, size
+            );
+        }
+    }
+    format!(
+        "{}\n{}#[allow(improper_ctypes)]\nextern \"C\" {{\n{}}}\n{}",
+        HEADER, bindings.structs, bindings.fncs, consts
+    )
+}
+
+/// Write the file, but don't touch it if nothing changed, since cargo would rebuild the crate.
+pub fn write_if_changed(path: &Path, content: &str) -> Result<(), EnzymeBuildError> {
+    if fs::read_to_string(path).ok().as_deref() == Some(content) {
+        return Ok(());
+    }
+    fs::write(path, content).map_err(|e| EnzymeBuildError::Io {
+        path: path.to_owned(),
+        message: e.to_string(),
+    })
+}
+
+/// Provide the declarations of the last build to the bitcode pass.
+pub fn prepare_bitcode_pass() -> Result<(), EnzymeBuildError> {
+    println!("cargo:rerun-if-env-changed={}", GRADIENTS_ENV);
+    let out_file = PathBuf::from(env_var("OUT_DIR")?).join(GRADIENTS_FILE);
+    let last_build = env::var(GRADIENTS_ENV).ok().map(PathBuf::from);
+    if let Some(last_build) = &last_build {
+        println!("cargo:rerun-if-changed={}", last_build.display());
+    }
+    match last_build.and_then(|path| fs::read_to_string(path).ok()) {
+        Some(content) => write_if_changed(&out_file, &content),
+        None => {
+            println!("cargo:rustc-cfg={}", BOOTSTRAP_CFG);
+            let content = HEADER.to_owned()
+                + "// The declarations will be generated once the bitcode of your crate exists.\n";
+            write_if_changed(&out_file, &content)
+        }
+    }
+}
+
+/// The Rust names of the parameters which the generated function takes over from the primal.
+/// Those are the primal parameters, each followed by its shadow if it's duplicated.
+unsafe fn primal_param_names(primal: LLVMValueRef, info: &FncInfo) -> Vec<Option<String>> {
+    let names = match dwarf::param_type_names(primal) {
+        Some(names) => names,
+        None => return vec![],
+    };
+    let mut res = vec![];
+    for (name, activity) in names.into_iter().zip(&info.params.input_activity) {
+        // We only need them to tell e.g. usize and i64 apart.
+        res.push(name.filter(|name| INTEGER_TYPES.contains(&name.as_str())));
+        if matches!(
+            activity,
+            CDIFFE_TYPE::DFT_DUP_ARG | CDIFFE_TYPE::DFT_DUP_NONEED
+        ) {
+            res.push(None);
+        }
+    }
+    res
+}
+
+struct Bindings {
+    structs: String,
+    fncs: String,
+}
+
+impl Bindings {
+    /// Errors hold the LLVM type which we can't express in Rust.
+    unsafe fn declare_function(
+        &mut self,
+        fnc: LLVMValueRef,
+        name: &str,
+        param_names: &[Option<String>],
+    ) -> Result<(), String> {
+        let mut params = vec![];
+        for (i, param) in get_params(fnc).into_iter().enumerate() {
+            let ty = LLVMTypeOf(param);
+            let rust_name = param_names.get(i).cloned().flatten();
+            match (LLVMGetTypeKind(ty), rust_name) {
+                // Vector mode passes the lanes of a shadow as array, handle_ffi expands them.
+                (LLVMTypeKind::LLVMArrayTypeKind, _) => {
+                    let element = self.rust_type(LLVMGetElementType(ty), name)?;
+                    for _ in 0..LLVMGetArrayLength(ty) {
+                        params.push(element.clone());
+                    }
+                }
+                (LLVMTypeKind::LLVMIntegerTypeKind, Some(rust_name)) => params.push(rust_name),
+                _ => params.push(self.rust_type(ty, name)?),
+            }
+        }
+        let params: Vec<String> = params
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| format!("arg{}: {}", i, ty))
+            .collect();
+
+        let return_type = LLVMGetReturnType(LLVMGetElementType(LLVMTypeOf(fnc)));
+        let ret = if LLVMGetTypeKind(return_type) == LLVMTypeKind::LLVMVoidTypeKind {
+            String::new()
+        } else {
+            " -> ".to_owned() + &self.rust_type(return_type, &(name.to_owned() + "_ret"))?
+        };
+        self.fncs += &format!("    pub fn {}({}){};\n", name, params.join(", "), ret);
+        Ok(())
+    }
+
+    /// `struct_name` is used if `ty` is a struct, which we then have to declare.
+    unsafe fn rust_type(&mut self, ty: LLVMTypeRef, struct_name: &str) -> Result<String, String> {
+        let unsupported = || Err(get_type(ty).to_string_lossy().into_owned());
+        Ok(match LLVMGetTypeKind(ty) {
+            LLVMTypeKind::LLVMDoubleTypeKind => "f64".to_owned(),
+            LLVMTypeKind::LLVMFloatTypeKind => "f32".to_owned(),
+            // LLVM doesn't know the signedness. Parameters take it from the debug info if they
+            // can, everything else is declared as signed.
+            LLVMTypeKind::LLVMIntegerTypeKind => match LLVMGetIntTypeWidth(ty) {
+                1 => "bool".to_owned(),
+                8 => "i8".to_owned(),
+                16 => "i16".to_owned(),
+                32 => "i32".to_owned(),
+                64 => "i64".to_owned(),
+                128 => "i128".to_owned(),
+                _ => return unsupported(),
+            },
+            LLVMTypeKind::LLVMPointerTypeKind => {
+                let pointee = LLVMGetElementType(ty);
+                let pointee = match LLVMGetTypeKind(pointee) {
+                    LLVMTypeKind::LLVMDoubleTypeKind
+                    | LLVMTypeKind::LLVMFloatTypeKind
+                    | LLVMTypeKind::LLVMIntegerTypeKind => self.rust_type(pointee, struct_name)?,
+                    _ => "core::ffi::c_void".to_owned(),
+                };
+                "*mut ".to_owned() + &pointee
+            }
+            LLVMTypeKind::LLVMArrayTypeKind => format!(
+                "[{}; {}]",
+                self.rust_type(LLVMGetElementType(ty), struct_name)?,
+                LLVMGetArrayLength(ty)
+            ),
+            LLVMTypeKind::LLVMStructTypeKind => self.declare_struct(ty, struct_name)?,
+            _ => return unsupported(),
+        })
+    }
+
+    unsafe fn declare_struct(&mut self, ty: LLVMTypeRef, name: &str) -> Result<String, String> {
+        let num_elements = LLVMCountStructElementTypes(ty) as usize;
+        let mut elements: Vec<LLVMTypeRef> = vec![ptr::null_mut(); num_elements];
+        LLVMGetStructElementTypes(ty, elements.as_mut_ptr());
+        let mut fields = String::new();
+        for (i, &element) in elements.iter().enumerate() {
+            let field = self.rust_type(element, &format!("{}_{}", name, i))?;
+            fields += &format!("    pub f{}: {},\n", i, field);
+        }
+        self.structs += &format!(
+            "#[allow(non_camel_case_types)]\n#[repr(C)]\n#[derive(Clone, Copy, Debug)]\npub struct {} {{\n{}}}\n\n",
+            name, fields
+        );
+        Ok(name.to_owned())
+    }
+}
@@ -0,0 +1,66 @@
+use crate::{Config, EnzymeBuildError, FncInfo};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+/// Remembers which inputs the archive in OUT_DIR was generated from, so we can skip
+/// the differentiation if nothing changed.
+/// The cache file holds the hash of those inputs.
+pub struct ArchiveCache {
+    path: PathBuf,
+    hash: u64,
+}
+
+impl ArchiveCache {
+    /// Hash everything which has an impact on the generated archive.
+    pub fn new(
+        out_dir: &Path,
+        merged_bc: &Path,
+        infos: &[FncInfo],
+        config: &Config,
+    ) -> Result<ArchiveCache, EnzymeBuildError> {
+        let bitcode = fs::read(merged_bc).map_err(|e| EnzymeBuildError::InvalidBitcode {
+            path: merged_bc.to_owned(),
+            message: e.to_string(),
+        })?;
+        let mut hasher = DefaultHasher::new();
+        bitcode.hash(&mut hasher);
+        format!("{:?}", infos).hash(&mut hasher);
+        config.hash(&mut hasher);
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        env!("RUSTC_VER").hash(&mut hasher);
+        env!("ENZYME_VER").hash(&mut hasher);
+        env!("LLVM_VER").hash(&mut hasher);
+
+        Ok(ArchiveCache {
+            path: out_dir.join("enzyme-cache"),
+            hash: hasher.finish(),
+        })
+    }
+
+    /// Was `archive` generated from the same inputs?
+    pub fn lookup(&self, archive: &Path) -> bool {
+        archive.exists()
+            && fs::read_to_string(&self.path)
+                .map(|content| content.trim() == self.hash.to_string())
+                .unwrap_or(false)
+    }
+
+    /// Forget the last run, since we are about to overwrite its archive.
+    pub fn clear(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+
+    /// Remember the inputs of the archive which we just generated.
+    /// If that fails we just regenerate the archive next time.
+    pub fn store(&self) {
+        if let Err(e) = fs::write(&self.path, self.hash.to_string()) {
+            println!(
+                "cargo:warning=Could not write the enzyme cache file {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use crate::dump::IrDumper;
+use crate::localize::DEFAULT_LOCAL_SYMBOLS;
+use crate::passes::PassPipeline;
+use crate::target::{rustflags_codegen_opt, Target};
+use crate::{env_var, EnzymeBuildError, TargetOptions};
+use llvm_sys::target_machine::{LLVMCodeGenOptLevel, LLVMCodeModel, LLVMRelocMode};
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// How much the generated functions get optimized, same as `-C opt-level`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+    O3,
+    Os,
+    Oz,
+}
+
+/// Same as `-C code-model`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CodeModel {
+    Tiny,
+    Small,
+    Kernel,
+    Medium,
+    Large,
+}
+
+/// Same as `-C relocation-model`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RelocMode {
+    Static,
+    Pic,
+    DynamicNoPic,
+}
+
+/// What we build from the generated functions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OutputKind {
+    /// A static library, which gets linked into your crate.
+    StaticLib,
+    /// A shared library, e.g. for plugins loaded at runtime.
+    CDylib,
+    /// A plain object file, passed to the linker of your binaries.
+    Object,
+}
+
+/// Settings for generating and compiling the functions, pass it to `build_with`.
+///
+/// Everything which isn't set explicitly is taken from the profile which cargo builds.
+///
+/// ```ignore
+/// let config = EnzymeConfig::new().with_opt_level(OptLevel::O3);
+/// oxide_enzyme::build_with(vec![info], config);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct EnzymeConfig {
+    opt_level: Option<OptLevel>,
+    code_model: Option<CodeModel>,
+    reloc_mode: Option<RelocMode>,
+    target: TargetOptions,
+    pre_ad_passes: Option<PassPipeline>,
+    post_ad_passes: Option<PassPipeline>,
+    local_symbols: Option<Vec<String>>,
+    extra_local_symbols: Vec<String>,
+    output: Option<OutputKind>,
+    lib_name: Option<String>,
+    dump_ir: Option<PathBuf>,
+}
+
+/// The fully resolved EnzymeConfig.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Config {
+    pub opt_level: OptLevel,
+    pub code_model: CodeModel,
+    pub reloc_mode: RelocMode,
+    pub target: Target,
+    pub pre_ad_passes: PassPipeline,
+    pub post_ad_passes: PassPipeline,
+    pub local_symbols: Vec<String>,
+    pub output: OutputKind,
+    pub lib_name: String,
+    pub dump_ir: Option<PathBuf>,
+    pub debug: bool,
+}
+
+impl EnzymeConfig {
+    pub fn new() -> EnzymeConfig {
+        EnzymeConfig::default()
+    }
+
+    /// Used by Enzyme and for the codegen. Defaults to cargo's `OPT_LEVEL`.
+    pub fn with_opt_level(mut self, opt_level: OptLevel) -> EnzymeConfig {
+        self.opt_level = Some(opt_level);
+        self
+    }
+
+    /// Defaults to `-C code-model` or Small.
+    pub fn with_code_model(mut self, code_model: CodeModel) -> EnzymeConfig {
+        self.code_model = Some(code_model);
+        self
+    }
+
+    /// Defaults to `-C relocation-model` or Pic.
+    pub fn with_reloc_mode(mut self, reloc_mode: RelocMode) -> EnzymeConfig {
+        self.reloc_mode = Some(reloc_mode);
+        self
+    }
+
+    /// Compile for another machine than the one cargo builds for.
+    pub fn with_target(mut self, target: TargetOptions) -> EnzymeConfig {
+        self.target = target;
+        self
+    }
+
+    /// Simplify the IR before Enzyme differentiates it, e.g. with `"mem2reg,sroa,inline"`.
+    /// Defaults to no passes.
+    pub fn with_pre_ad_passes(mut self, passes: PassPipeline) -> EnzymeConfig {
+        self.pre_ad_passes = Some(passes);
+        self
+    }
+
+    /// Optimize the generated functions before they get compiled.
+    /// Defaults to LLVM's pipeline for the opt level.
+    pub fn with_post_ad_passes(mut self, passes: PassPipeline) -> EnzymeConfig {
+        self.post_ad_passes = Some(passes);
+        self
+    }
+
+    /// Hide another symbol of the generated object file from the linker,
+    /// in addition to the runtime symbols which we hide by default.
+    pub fn with_local_symbol(mut self, symbol: &str) -> EnzymeConfig {
+        self.extra_local_symbols.push(symbol.to_owned());
+        self
+    }
+
+    /// Replace the list of runtime symbols which we hide by default,
+    /// see `DEFAULT_LOCAL_SYMBOLS`.
+    pub fn with_local_symbols(mut self, symbols: Vec<String>) -> EnzymeConfig {
+        self.local_symbols = Some(symbols);
+        self
+    }
+
+    /// Defaults to a static library.
+    pub fn with_output(mut self, output: OutputKind) -> EnzymeConfig {
+        self.output = Some(output);
+        self
+    }
+
+    /// The name of the library (or object file) without prefix and extension.
+    /// Defaults to `GradFunc_<crate name>`, so crates in one workspace don't clash.
+    pub fn with_lib_name(mut self, name: &str) -> EnzymeConfig {
+        self.lib_name = Some(name.to_owned());
+        self
+    }
+
+    /// Write the module to `dir` after each of our steps, as `.ll` and `.bc`.
+    /// Relative dirs start at your crate, where cargo runs build.rs.
+    /// The `ENZYME_DUMP_IR` env var overrides it. Defaults to no dumping.
+    pub fn with_dump_ir(mut self, dir: &Path) -> EnzymeConfig {
+        self.dump_ir = Some(dir.to_owned());
+        self
+    }
+
+    pub(crate) fn resolve(&self) -> Result<Config, EnzymeBuildError> {
+        let opt_level = match self.opt_level {
+            Some(opt_level) => opt_level,
+            None => match env::var("OPT_LEVEL") {
+                Ok(level) => OptLevel::parse(&level)?,
+                Err(_) if env_var("PROFILE")? == "release" => OptLevel::O3,
+                Err(_) => OptLevel::O0,
+            },
+        };
+        let code_model = match self.code_model {
+            Some(code_model) => code_model,
+            None => match rustflags_codegen_opt("code-model") {
+                Some(model) => CodeModel::parse(&model)?,
+                None => CodeModel::Small,
+            },
+        };
+        let reloc_mode = match self.reloc_mode {
+            Some(reloc_mode) => reloc_mode,
+            None => match rustflags_codegen_opt("relocation-model") {
+                Some(model) => RelocMode::parse(&model)?,
+                None => RelocMode::Pic,
+            },
+        };
+        let mut local_symbols = self.local_symbols.clone().unwrap_or_else(|| {
+            DEFAULT_LOCAL_SYMBOLS
+                .iter()
+                .map(|symbol| symbol.to_string())
+                .collect()
+        });
+        local_symbols.extend(self.extra_local_symbols.iter().cloned());
+        let lib_name = match &self.lib_name {
+            Some(name) => name.clone(),
+            None => "GradFunc_".to_owned() + &env_var("CARGO_PKG_NAME")?.replace('-', "_"),
+        };
+        let dump_ir =
+            IrDumper::dir_from_env(Path::new(&env_var("OUT_DIR")?), self.dump_ir.clone())?;
+        Ok(Config {
+            opt_level,
+            code_model,
+            reloc_mode,
+            target: self.target.resolve()?,
+            pre_ad_passes: self.pre_ad_passes.clone().unwrap_or(PassPipeline::None),
+            post_ad_passes: self
+                .post_ad_passes
+                .clone()
+                .unwrap_or(PassPipeline::Preset(opt_level)),
+            local_symbols,
+            output: self.output.unwrap_or(OutputKind::StaticLib),
+            lib_name,
+            dump_ir,
+            debug: env_var("DEBUG")? == "true",
+        })
+    }
+}
+
+fn unsupported(option: &str, value: &str) -> EnzymeBuildError {
+    EnzymeBuildError::InvalidConfig {
+        option: option.to_owned(),
+        message: format!(
+            "We don't support {}, please set it through the EnzymeConfig instead.",
+            value
+        ),
+    }
+}
+
+impl OptLevel {
+    fn parse(level: &str) -> Result<OptLevel, EnzymeBuildError> {
+        match level {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            "3" => Ok(OptLevel::O3),
+            "s" => Ok(OptLevel::Os),
+            "z" => Ok(OptLevel::Oz),
+            _ => Err(unsupported("opt-level", level)),
+        }
+    }
+
+    /// Enzyme only distinguishes between optimizing or not.
+    pub(crate) fn optimize(self) -> bool {
+        self != OptLevel::O0
+    }
+
+    pub(crate) fn to_llvm(self) -> LLVMCodeGenOptLevel {
+        match self {
+            OptLevel::O0 => LLVMCodeGenOptLevel::LLVMCodeGenLevelNone,
+            OptLevel::O1 => LLVMCodeGenOptLevel::LLVMCodeGenLevelLess,
+            OptLevel::O2 | OptLevel::Os | OptLevel::Oz => {
+                LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault
+            }
+            OptLevel::O3 => LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+        }
+    }
+}
+
+impl CodeModel {
+    fn parse(model: &str) -> Result<CodeModel, EnzymeBuildError> {
+        match model {
+            "tiny" => Ok(CodeModel::Tiny),
+            "small" => Ok(CodeModel::Small),
+            "kernel" => Ok(CodeModel::Kernel),
+            "medium" => Ok(CodeModel::Medium),
+            "large" => Ok(CodeModel::Large),
+            _ => Err(unsupported("code-model", model)),
+        }
+    }
+
+    pub(crate) fn to_llvm(self) -> LLVMCodeModel {
+        match self {
+            CodeModel::Tiny => LLVMCodeModel::LLVMCodeModelTiny,
+            CodeModel::Small => LLVMCodeModel::LLVMCodeModelSmall,
+            CodeModel::Kernel => LLVMCodeModel::LLVMCodeModelKernel,
+            CodeModel::Medium => LLVMCodeModel::LLVMCodeModelMedium,
+            CodeModel::Large => LLVMCodeModel::LLVMCodeModelLarge,
+        }
+    }
+}
+
+impl RelocMode {
+    fn parse(model: &str) -> Result<RelocMode, EnzymeBuildError> {
+        match model {
+            "static" => Ok(RelocMode::Static),
+            "pic" | "pie" | "default" => Ok(RelocMode::Pic),
+            "dynamic-no-pic" => Ok(RelocMode::DynamicNoPic),
+            _ => Err(unsupported("relocation-model", model)),
+        }
+    }
+
+    pub(crate) fn to_llvm(self) -> LLVMRelocMode {
+        match self {
+            RelocMode::Static => LLVMRelocMode::LLVMRelocStatic,
+            RelocMode::Pic => LLVMRelocMode::LLVMRelocPIC,
+            RelocMode::DynamicNoPic => LLVMRelocMode::LLVMRelocDynamicNoPic,
+        }
+    }
+}
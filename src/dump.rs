@@ -0,0 +1,83 @@
+use crate::{c_path, EnzymeBuildError};
+use llvm_sys::bit_writer::LLVMWriteBitcodeToFile;
+use llvm_sys::core::{LLVMDisposeMessage, LLVMPrintModuleToFile};
+use llvm_sys::prelude::*;
+use std::env;
+use std::ffi::CStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+/// Set it (to anything but 0) to write the module to OUT_DIR/ir after each of our steps.
+/// It overrides `EnzymeConfig::with_dump_ir`, 0 disables dumping.
+pub const DUMP_IR_ENV: &str = "ENZYME_DUMP_IR";
+
+/// Writes the module as `<stage>.ll` and `<stage>.bc`, if the user asked for it.
+pub struct IrDumper {
+    dir: Option<PathBuf>,
+}
+
+impl IrDumper {
+    /// Dumps into `dir`, nothing if it is None. The stages of previous runs get overwritten,
+    /// but we don't clear the dir, since the user might have chosen one holding other files.
+    pub fn new(dir: Option<&Path>) -> Result<IrDumper, EnzymeBuildError> {
+        let dir = match dir {
+            Some(dir) => dir.to_owned(),
+            None => return Ok(IrDumper { dir: None }),
+        };
+        fs::create_dir_all(&dir).map_err(|e| EnzymeBuildError::Io {
+            path: dir.clone(),
+            message: e.to_string(),
+        })?;
+        println!("IR stages will be written to {}", dir.display());
+        Ok(IrDumper { dir: Some(dir) })
+    }
+
+    /// The dir to dump into, taking `DUMP_IR_ENV` into account.
+    pub fn dir_from_env(
+        out_dir: &Path,
+        configured: Option<PathBuf>,
+    ) -> Result<Option<PathBuf>, EnzymeBuildError> {
+        println!("cargo:rerun-if-env-changed={}", DUMP_IR_ENV);
+        Ok(match env::var(DUMP_IR_ENV) {
+            Ok(val) if val == "0" => None,
+            Ok(val) if !val.is_empty() => Some(out_dir.join("ir")),
+            _ => configured,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    pub fn dump(&self, module: LLVMModuleRef, stage: &str) -> Result<(), EnzymeBuildError> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        let ll_path = dir.join(stage.to_owned() + ".ll");
+        let c_ll_path = c_path(&ll_path)?;
+        let mut msg = ptr::null_mut();
+        if unsafe { LLVMPrintModuleToFile(module, c_ll_path.as_ptr(), &mut msg) } != 0 {
+            let message = unsafe { CStr::from_ptr(msg) }
+                .to_string_lossy()
+                .into_owned();
+            unsafe { LLVMDisposeMessage(msg) };
+            return Err(EnzymeBuildError::Io {
+                path: ll_path,
+                message,
+            });
+        }
+
+        let bc_path = dir.join(stage.to_owned() + ".bc");
+        let c_bc_path = c_path(&bc_path)?;
+        if unsafe { LLVMWriteBitcodeToFile(module, c_bc_path.as_ptr()) } != 0 {
+            return Err(EnzymeBuildError::Io {
+                path: bc_path,
+                message: "Could not write the bitcode.".to_owned(),
+            });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,402 @@
+//! Build Enzyme type trees based on the DWARF debug info which rustc attaches to the IR.
+//!
+//! Rust lowers many types to opaque `{ i8*, i64 }`-style LLVM types, so the LLVM types alone
+//! often don't tell whether some memory holds floats. The debug info still knows the Rust types.
+use crate::enzyme::tree::{MAX_ARRAY_ELEMENTS, MAX_POINTER_DEPTH};
+use crate::enzyme::{CConcreteType, TypeTree};
+use crate::wrappers::get_params;
+use llvm_sys::core::*;
+use llvm_sys::debuginfo::*;
+use llvm_sys::prelude::*;
+use llvm_sys::LLVMTypeKind;
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+// Operand positions within the metadata nodes, see llvm/IR/DebugInfoMetadata.h
+const SUBPROGRAM_TYPE: usize = 4;
+const SUBROUTINE_TYPE_ARRAY: usize = 3;
+const BASE_TYPE: usize = 3;
+const COMPOSITE_ELEMENTS: usize = 4;
+
+struct DwarfCtx {
+    context: LLVMContextRef,
+    layout: String,
+}
+
+impl DwarfCtx {
+    unsafe fn new(fnc: LLVMValueRef) -> DwarfCtx {
+        let module = LLVMGetGlobalParent(fnc);
+        DwarfCtx {
+            context: LLVMGetModuleContext(module),
+            layout: CStr::from_ptr(LLVMGetDataLayoutStr(module))
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+}
+
+/// Build one type tree per LLVM parameter of `fnc` based on its debug info.
+///
+/// Returns None if the function has no debug info, or if we can't tell which
+/// Rust parameter belongs to which LLVM parameter.
+///
+/// # Safety
+///
+/// `fnc` must be a valid function, which is part of a module.
+pub unsafe fn param_type_trees(fnc: LLVMValueRef) -> Option<Vec<TypeTree>> {
+    let subprogram = LLVMGetSubprogram(fnc);
+    if subprogram.is_null() {
+        return None;
+    }
+    let ctx = DwarfCtx::new(fnc);
+
+    let subroutine_type = operand(&ctx, subprogram, SUBPROGRAM_TYPE)?;
+    let type_array = operand(&ctx, subroutine_type, SUBROUTINE_TYPE_ARRAY)?;
+    // The first entry is the return type, all others are the parameters.
+    let mut dwarf_types = operands(&ctx, type_array);
+    if dwarf_types.is_empty() {
+        return None;
+    }
+    let ret = dwarf_types.remove(0);
+
+    let llvm_params: Vec<LLVMTypeRef> = get_params(fnc)
+        .iter()
+        .map(|&param| LLVMTypeOf(param))
+        .collect();
+
+    let mut trees = vec![];
+    let mut llvm_params = llvm_params.as_slice();
+
+    // Returning larger structs moves the return value into the first parameter.
+    if has_sret(fnc) {
+        let ret = ret?;
+        trees.push(pointer_value_tree(&ctx, ret));
+        llvm_params = &llvm_params[1..];
+    }
+
+    for (i, dwarf_type) in dwarf_types.iter().enumerate() {
+        let dwarf_type = (*dwarf_type)?;
+        let remaining_dwarf = dwarf_types.len() - i;
+
+        // Zero sized types like PhantomData don't show up in LLVM.
+        if LLVMDITypeGetSizeInBits(dwarf_type) == 0 && !is_pointer(&ctx, dwarf_type) {
+            continue;
+        }
+
+        let llvm_param = *llvm_params.first()?;
+        let leaves = scalar_leaves(&ctx, dwarf_type);
+        if leaves.len() == 2 && llvm_params.len() > remaining_dwarf {
+            // Rust passes pairs of scalars like slices as two separate parameters.
+            for leaf in leaves {
+                trees.push(value_tree(&ctx, leaf, *llvm_params.first()?));
+                llvm_params = &llvm_params[1..];
+            }
+        } else {
+            let tree = if LLVMGetTypeKind(llvm_param) == LLVMTypeKind::LLVMPointerTypeKind
+                && !is_pointer(&ctx, dwarf_type)
+            {
+                // Larger structs are passed indirectly
+                pointer_value_tree(&ctx, dwarf_type)
+            } else if leaves.len() == 1 {
+                value_tree(&ctx, leaves[0], llvm_param)
+            } else {
+                value_tree(&ctx, dwarf_type, llvm_param)
+            };
+            trees.push(tree);
+            llvm_params = &llvm_params[1..];
+        }
+    }
+
+    if llvm_params.is_empty() {
+        Some(trees)
+    } else {
+        None
+    }
+}
+
+/// The Rust names of primitive parameter types like `usize`, one entry per LLVM parameter
+/// of `fnc`. Parameters of other types have no name.
+///
+/// Returns None under the same conditions as `param_type_trees`.
+///
+/// # Safety
+///
+/// `fnc` must be a valid function, which is part of a module.
+pub unsafe fn param_type_names(fnc: LLVMValueRef) -> Option<Vec<Option<String>>> {
+    let subprogram = LLVMGetSubprogram(fnc);
+    if subprogram.is_null() || has_sret(fnc) {
+        return None;
+    }
+    let ctx = DwarfCtx::new(fnc);
+
+    let subroutine_type = operand(&ctx, subprogram, SUBPROGRAM_TYPE)?;
+    let type_array = operand(&ctx, subroutine_type, SUBROUTINE_TYPE_ARRAY)?;
+    let dwarf_types = operands(&ctx, type_array);
+    let num_llvm_params = LLVMCountParams(fnc) as usize;
+
+    let mut names = vec![];
+    // The first entry is the return type
+    for (i, dwarf_type) in dwarf_types.iter().enumerate().skip(1) {
+        let dwarf_type = (*dwarf_type)?;
+        let remaining_dwarf = dwarf_types.len() - i;
+        if LLVMDITypeGetSizeInBits(dwarf_type) == 0 && !is_pointer(&ctx, dwarf_type) {
+            continue;
+        }
+        let leaves = scalar_leaves(&ctx, dwarf_type);
+        if leaves.len() == 2 && num_llvm_params - names.len() > remaining_dwarf {
+            names.push(basic_type_name(leaves[0]));
+            names.push(basic_type_name(leaves[1]));
+        } else if leaves.len() == 1 {
+            names.push(basic_type_name(leaves[0]));
+        } else {
+            names.push(None);
+        }
+    }
+
+    if names.len() == num_llvm_params {
+        Some(names)
+    } else {
+        None
+    }
+}
+
+/// The tree of a parameter, describing the value itself at offset -1.
+unsafe fn value_tree(ctx: &DwarfCtx, ty: LLVMMetadataRef, llvm_param: LLVMTypeRef) -> TypeTree {
+    if is_pointer(ctx, ty) {
+        return pointer_tree(ctx, ty, 0).only(-1);
+    }
+    match LLVMGetMetadataKind(ty) {
+        LLVMMetadataKind::LLVMDIBasicTypeMetadataKind => match basic_type(ty) {
+            Some(t) => TypeTree::from_type(t, ctx.context).only(-1),
+            None => TypeTree::new(),
+        },
+        // Small aggregates passed by value are described by their memory.
+        _ if LLVMGetTypeKind(llvm_param) != LLVMTypeKind::LLVMPointerTypeKind => {
+            memory_tree(ctx, ty, 0)
+        }
+        _ => TypeTree::new(),
+    }
+}
+
+/// The tree of a pointer parameter, pointing to memory of the given type.
+unsafe fn pointer_value_tree(ctx: &DwarfCtx, pointee: LLVMMetadataRef) -> TypeTree {
+    TypeTree::from_type(CConcreteType::DT_Pointer, ctx.context)
+        .merge(memory_tree(ctx, pointee, 1))
+        .only(-1)
+}
+
+/// A pointer, merged with the memory it points to.
+/// Scalar pointees are assumed to be arrays (Vec, slices, ...), so they are valid at any offset.
+unsafe fn pointer_tree(ctx: &DwarfCtx, ptr: LLVMMetadataRef, depth: u32) -> TypeTree {
+    let tree = TypeTree::from_type(CConcreteType::DT_Pointer, ctx.context);
+    let pointee = match operand(ctx, ptr, BASE_TYPE) {
+        Some(pointee) if depth < MAX_POINTER_DEPTH => pointee,
+        _ => return tree,
+    };
+    let pointee_tree = match basic_type(pointee) {
+        Some(t) => TypeTree::from_type(t, ctx.context).only(-1),
+        None => memory_tree(ctx, pointee, depth + 1),
+    };
+    tree.merge(pointee_tree)
+}
+
+/// The tree describing memory of the given type, starting at offset 0.
+unsafe fn memory_tree(ctx: &DwarfCtx, ty: LLVMMetadataRef, depth: u32) -> TypeTree {
+    if is_pointer(ctx, ty) {
+        return pointer_tree(ctx, ty, depth).only(0);
+    }
+    match LLVMGetMetadataKind(ty) {
+        LLVMMetadataKind::LLVMDIBasicTypeMetadataKind => match basic_type(ty) {
+            Some(t) => TypeTree::from_type(t, ctx.context).only(0),
+            None => TypeTree::new(),
+        },
+        LLVMMetadataKind::LLVMDICompositeTypeMetadataKind if is_array(ctx, ty) => {
+            let element = match operand(ctx, ty, BASE_TYPE) {
+                Some(element) => element,
+                None => return TypeTree::new(),
+            };
+            let element_size = LLVMDITypeGetSizeInBits(element) / 8;
+            if element_size == 0 {
+                return TypeTree::new();
+            }
+            let num_elements =
+                (LLVMDITypeGetSizeInBits(ty) / 8 / element_size).min(MAX_ARRAY_ELEMENTS);
+            let element_tree = memory_tree(ctx, element, depth);
+            let mut tree = TypeTree::new();
+            for i in 0..num_elements {
+                tree = tree.merge(element_tree.clone().shift(
+                    &ctx.layout,
+                    0,
+                    element_size as isize,
+                    (i * element_size) as usize,
+                ));
+            }
+            tree
+        }
+        LLVMMetadataKind::LLVMDICompositeTypeMetadataKind => {
+            let mut tree = TypeTree::new();
+            for (member, member_type) in members(ctx, ty) {
+                let member_size = LLVMDITypeGetSizeInBits(member_type) / 8;
+                let member_offset = LLVMDITypeGetOffsetInBits(member) / 8;
+                tree = tree.merge(memory_tree(ctx, member_type, depth).shift(
+                    &ctx.layout,
+                    0,
+                    member_size as isize,
+                    member_offset as usize,
+                ));
+            }
+            tree
+        }
+        _ => TypeTree::new(),
+    }
+}
+
+/// Collect the scalar fields of (nested) structs, Rust passes structs consisting of
+/// one or two scalars directly instead of by pointer.
+unsafe fn scalar_leaves(ctx: &DwarfCtx, ty: LLVMMetadataRef) -> Vec<LLVMMetadataRef> {
+    if is_pointer(ctx, ty)
+        || matches!(
+            LLVMGetMetadataKind(ty),
+            LLVMMetadataKind::LLVMDIBasicTypeMetadataKind
+        )
+    {
+        return vec![ty];
+    }
+    if !matches!(
+        LLVMGetMetadataKind(ty),
+        LLVMMetadataKind::LLVMDICompositeTypeMetadataKind
+    ) || is_array(ctx, ty)
+    {
+        return vec![];
+    }
+    members(ctx, ty)
+        .into_iter()
+        .filter(|&(_, member_type)| LLVMDITypeGetSizeInBits(member_type) != 0)
+        .flat_map(|(_, member_type)| scalar_leaves(ctx, member_type))
+        .collect()
+}
+
+/// The members of a struct together with their types
+unsafe fn members(ctx: &DwarfCtx, ty: LLVMMetadataRef) -> Vec<(LLVMMetadataRef, LLVMMetadataRef)> {
+    let elements = match operand(ctx, ty, COMPOSITE_ELEMENTS) {
+        Some(elements) => elements,
+        None => return vec![],
+    };
+    operands(ctx, elements)
+        .into_iter()
+        .flatten()
+        .filter(|&member| {
+            matches!(
+                LLVMGetMetadataKind(member),
+                LLVMMetadataKind::LLVMDIDerivedTypeMetadataKind
+            )
+        })
+        .filter_map(|member| Some((member, operand(ctx, member, BASE_TYPE)?)))
+        .collect()
+}
+
+/// Pointers and references. Typedefs, members and qualifiers are derived types as well,
+/// so we have to check the tag.
+unsafe fn is_pointer(ctx: &DwarfCtx, ty: LLVMMetadataRef) -> bool {
+    matches!(
+        LLVMGetMetadataKind(ty),
+        LLVMMetadataKind::LLVMDIDerivedTypeMetadataKind
+    ) && matches!(
+        dwarf_tag(ctx, ty).as_deref(),
+        Some("DW_TAG_pointer_type") | Some("DW_TAG_reference_type")
+    )
+}
+
+/// The tag of a debug info node, e.g. `DW_TAG_pointer_type`.
+/// The C API of LLVM 13 has no getter for it, so we read it from the printed node.
+unsafe fn dwarf_tag(ctx: &DwarfCtx, md: LLVMMetadataRef) -> Option<String> {
+    let printed = LLVMPrintValueToString(LLVMMetadataAsValue(ctx.context, md));
+    let text = CStr::from_ptr(printed).to_string_lossy().into_owned();
+    LLVMDisposeMessage(printed);
+    let start = text.find("tag: ")? + "tag: ".len();
+    text[start..]
+        .split([',', ')'])
+        .next()
+        .map(|tag| tag.trim().to_owned())
+}
+
+unsafe fn is_array(ctx: &DwarfCtx, ty: LLVMMetadataRef) -> bool {
+    match operand(ctx, ty, COMPOSITE_ELEMENTS) {
+        Some(elements) => operands(ctx, elements)
+            .into_iter()
+            .flatten()
+            .any(|element| {
+                matches!(
+                    LLVMGetMetadataKind(element),
+                    LLVMMetadataKind::LLVMDISubrangeMetadataKind
+                )
+            }),
+        None => false,
+    }
+}
+
+unsafe fn basic_type(ty: LLVMMetadataRef) -> Option<CConcreteType> {
+    if !matches!(
+        LLVMGetMetadataKind(ty),
+        LLVMMetadataKind::LLVMDIBasicTypeMetadataKind
+    ) {
+        return None;
+    }
+    let mut len = 0;
+    let name = LLVMDITypeGetName(ty, &mut len);
+    let name = std::slice::from_raw_parts(name as *const u8, len);
+    match name {
+        b"f64" => Some(CConcreteType::DT_Double),
+        b"f32" => Some(CConcreteType::DT_Float),
+        b"u8" | b"u16" | b"u32" | b"u64" | b"u128" | b"usize" | b"i8" | b"i16" | b"i32"
+        | b"i64" | b"i128" | b"isize" | b"bool" | b"char" => Some(CConcreteType::DT_Integer),
+        _ => None,
+    }
+}
+
+unsafe fn basic_type_name(ty: LLVMMetadataRef) -> Option<String> {
+    if !matches!(
+        LLVMGetMetadataKind(ty),
+        LLVMMetadataKind::LLVMDIBasicTypeMetadataKind
+    ) {
+        return None;
+    }
+    let mut len = 0;
+    let name = LLVMDITypeGetName(ty, &mut len);
+    let name = std::slice::from_raw_parts(name as *const u8, len);
+    Some(String::from_utf8_lossy(name).into_owned())
+}
+
+/// Does the function return its value through the pointer in its first parameter?
+///
+/// # Safety
+///
+/// `fnc` must be a valid function.
+pub unsafe fn has_sret(fnc: LLVMValueRef) -> bool {
+    let name = CString::new("sret").unwrap();
+    let kind = LLVMGetEnumAttributeKindForName(name.as_ptr(), 4);
+    // Attribute index 1 is the first parameter
+    LLVMCountParams(fnc) > 0 && !LLVMGetEnumAttributeAtIndex(fnc, 1, kind).is_null()
+}
+
+/// The operand at position `index` of a metadata node, None for missing operands
+unsafe fn operand(ctx: &DwarfCtx, md: LLVMMetadataRef, index: usize) -> Option<LLVMMetadataRef> {
+    operands(ctx, md).get(index).copied().flatten()
+}
+
+unsafe fn operands(ctx: &DwarfCtx, md: LLVMMetadataRef) -> Vec<Option<LLVMMetadataRef>> {
+    let node = LLVMMetadataAsValue(ctx.context, md);
+    let num_operands = LLVMGetMDNodeNumOperands(node) as usize;
+    let mut values: Vec<LLVMValueRef> = vec![ptr::null_mut(); num_operands];
+    LLVMGetMDNodeOperands(node, values.as_mut_ptr());
+    values
+        .into_iter()
+        .map(|value| {
+            if value.is_null() || LLVMIsAMDNode(value).is_null() {
+                None
+            } else {
+                Some(LLVMValueAsMetadata(value))
+            }
+        })
+        .collect()
+}
@@ -1,13 +1,17 @@
 // TODO: Verify wether to import the LLVM* from enzyme_sys, or from llvm-sys
+pub use enzyme_sys::{CConcreteType, LLVMOpaqueValue, CDIFFE_TYPE};
 use enzyme_sys::{
-    CDerivativeMode, CFnTypeInfo, EnzymeCreatePrimalAndGradient, EnzymeLogicRef,
-    EnzymeTypeAnalysisRef, FreeEnzymeLogic, FreeTypeAnalysis, IntList,
+    CDerivativeMode, CFnTypeInfo, EnzymeAugmentedReturnPtr, EnzymeCreateAugmentedPrimal,
+    EnzymeCreateForwardDiff, EnzymeCreatePrimalAndGradient, EnzymeExtractFunctionFromAugmentation,
+    EnzymeExtractTapeTypeFromAugmentation, EnzymeLogicRef, EnzymeTypeAnalysisRef, FreeEnzymeLogic,
+    FreeTypeAnalysis, IntList,
+};
+use enzyme_sys::{
+    CTypeTreeRef, CreateEnzymeLogic, CreateTypeAnalysis, EnzymeSetCLBool, LLVMTypeRef, LLVMValueRef,
 };
-use enzyme_sys::{CreateEnzymeLogic, CreateTypeAnalysis, EnzymeSetCLBool, LLVMValueRef};
-pub use enzyme_sys::{LLVMOpaqueValue, CDIFFE_TYPE};
 
 use super::enzyme_sys;
-use super::tree::TypeTree;
+use super::tree::FncTypeInfo;
 
 use std::os::raw::c_void;
 use std::ptr;
@@ -43,29 +47,59 @@ pub fn enzyme_print_functions(val: bool) {
 }
 
 /// Should be given by Enzyme users to declare how arguments shall be handled
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct FncInfo {
     pub primary_name: String, // What's the (unmangled) name of the Rust function to differentiate?
     pub grad_name: String,
+    pub aug_name: Option<String>, // Only used in split mode, name of the augmented primal
     pub params: ParamInfos,
+    pub pattern: bool, // Is primary_name a glob selecting several functions?
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct ParamInfos {
     pub input_activity: Vec<CDIFFE_TYPE>, // How should it's arguments be treated?
     pub ret_info: ReturnActivity,
+    pub mode: DiffMode, // Which Enzyme entry point generates the new function?
+    pub width: u32,     // How many tangents / adjoints are propagated at once?
+    pub uncacheable: Vec<bool>, // Is the memory behind an argument overwritten after the call?
+    pub known_values: Vec<Vec<i64>>, // Values which integer arguments are known to take
+}
+
+/// Selects the kind of derivative function which Enzyme should generate
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum DiffMode {
+    /// Propagate tangents along with the primal values (forward mode).
+    Forward,
+    /// Run the primal and afterwards propagate the adjoints back (combined reverse mode).
+    Reverse,
+    /// Generate an augmented primal returning a tape and a separate reverse pass
+    /// consuming that tape (split reverse mode).
+    ReverseSplit,
 }
 
 impl FncInfo {
     /// Enzyme requires one FncInfo Struct per function differentiation
     ///
     /// primary_name should be identical to the name of the existing rust function.
+    /// That's either its symbol name (e.g. for `#[no_mangle]` functions), or its Rust path
+    /// like `my_crate::physics::energy`, `crate::physics::energy` or `<my_crate::Foo>::energy`.
     ///
     /// grad_name will be the name of the generated rust function.
     ///
     /// ret_info should be None if the primary function has no return type.
     /// Otherwise it should specify if we want the output's gradient and if we want
     /// the return value of the primal rust function.
+    /// Duplicated and DuplicatedNoNeed returns aren't supported here, since the shadow
+    /// would only be returned after the reverse pass ran. Use `new_split` or `new_fwd` for them.
+    ///
+    /// Pointer arguments can be DFT_DUP_ARG, the shadow is expected directly after the
+    /// primal pointer. DFT_DUP_NONEED works the same, but tells Enzyme that the values
+    /// written through the primal pointer aren't needed afterwards.
+    ///
+    /// primary_name can also be the grad_name of another FncInfo, to differentiate the
+    /// generated function again (e.g. forward over reverse for Hessian-vector products).
+    /// The activities then have to match the signature of the generated function.
     pub fn new(
         primary_name: &str,
         grad_name: &str,
@@ -75,12 +109,170 @@ impl FncInfo {
         FncInfo {
             primary_name: primary_name.to_string(),
             grad_name: grad_name.to_string(),
+            aug_name: None,
+            params: ParamInfos {
+                input_activity,
+                ret_info,
+                mode: DiffMode::Reverse,
+                width: 1,
+                uncacheable: vec![],
+                known_values: vec![],
+            },
+            pattern: false,
+        }
+    }
+
+    /// Same as `new`, but Enzyme will generate a forward-mode (tangent) function.
+    ///
+    /// Arguments can only be Constant or Duplicated, since there is no output gradient
+    /// to collect. Duplicated arguments expect their tangent directly after the primal value.
+    ///
+    /// ret_info should be Active to return the primal value and its tangent, Gradient to only
+    /// return the tangent and Constant to only return the primal value.
+    pub fn new_fwd(
+        primary_name: &str,
+        grad_name: &str,
+        input_activity: Vec<CDIFFE_TYPE>,
+        ret_info: ReturnActivity,
+    ) -> FncInfo {
+        FncInfo {
+            primary_name: primary_name.to_string(),
+            grad_name: grad_name.to_string(),
+            aug_name: None,
+            params: ParamInfos {
+                input_activity,
+                ret_info,
+                mode: DiffMode::Forward,
+                width: 1,
+                uncacheable: vec![],
+                known_values: vec![],
+            },
+            pattern: false,
+        }
+    }
+
+    /// Same as `new`, but Enzyme will split the reverse mode into two functions.
+    ///
+    /// aug_name will be the name of the augmented primal. It takes the same arguments as the
+    /// combined gradient would, runs the primal and returns the tape, followed by the primal
+    /// return value and the shadow return value if requested through ret_info.
+    /// The tape is a pointer to memory allocated by Enzyme. Its size in bytes is exported as
+    /// `ENZYME_TAPE_SIZE_<grad_name>` in gradients.rs, 0 means that there is no tape since
+    /// Enzyme doesn't need to cache anything.
+    ///
+    /// grad_name will be the name of the reverse pass. It takes the same arguments as the
+    /// combined gradient would, followed by the tape returned from the augmented primal.
+    /// The reverse pass frees the tape, so every tape has to be passed to it exactly once.
+    pub fn new_split(
+        primary_name: &str,
+        aug_name: &str,
+        grad_name: &str,
+        input_activity: Vec<CDIFFE_TYPE>,
+        ret_info: ReturnActivity,
+    ) -> FncInfo {
+        FncInfo {
+            primary_name: primary_name.to_string(),
+            grad_name: grad_name.to_string(),
+            aug_name: Some(aug_name.to_string()),
             params: ParamInfos {
                 input_activity,
                 ret_info,
+                mode: DiffMode::ReverseSplit,
+                width: 1,
+                uncacheable: vec![],
+                known_values: vec![],
             },
+            pattern: false,
         }
     }
+
+    /// Differentiate every function whose name matches the glob `pattern`, e.g. `loss_*` for
+    /// `#[no_mangle]` functions or `crate::losses::*` for Rust paths.
+    ///
+    /// Each match gets its own gradient named `d_<name>`, where name is the last segment of
+    /// its path, or the whole path if several matches share their last segment.
+    /// In split mode the augmented primal is named `aug_<name>`.
+    ///
+    /// The activities are inferred from the signature: float arguments are Active (Duplicated
+    /// in forward mode), pointers are Duplicated and integers Constant, if the debug info
+    /// confirms that they are integers. Float return values are Active, pointers Duplicated
+    /// (Constant in combined reverse mode). Matches with other types are skipped with a warning.
+    pub fn new_pattern(pattern: &str, mode: DiffMode) -> FncInfo {
+        FncInfo {
+            primary_name: pattern.to_string(),
+            grad_name: String::new(),
+            aug_name: None,
+            params: ParamInfos {
+                input_activity: vec![],
+                ret_info: ReturnActivity::None,
+                mode,
+                width: 1,
+                uncacheable: vec![],
+                known_values: vec![],
+            },
+            pattern: true,
+        }
+    }
+
+    /// The FncInfo for a single function matched by a pattern FncInfo.
+    pub(crate) fn matched(
+        &self,
+        primary_name: &str,
+        name: &str,
+        input_activity: Vec<CDIFFE_TYPE>,
+        ret_info: ReturnActivity,
+    ) -> FncInfo {
+        let mut info = self.clone();
+        info.primary_name = primary_name.to_string();
+        info.grad_name = format!("d_{}", name);
+        if info.params.mode == DiffMode::ReverseSplit {
+            info.aug_name = Some(format!("aug_{}", name));
+        }
+        info.params.input_activity = input_activity;
+        info.params.ret_info = ret_info;
+        info.pattern = false;
+        info
+    }
+
+    /// Names of all functions which will be generated for this FncInfo,
+    /// in the order in which they are generated.
+    pub(crate) fn generated_names(&self) -> Vec<String> {
+        match &self.aug_name {
+            Some(aug_name) => vec![aug_name.clone(), self.grad_name.clone()],
+            None => vec![self.grad_name.clone()],
+        }
+    }
+
+    /// Let the generated function propagate `width` tangents (forward mode) or
+    /// adjoints (reverse mode) at once.
+    ///
+    /// Every shadow argument, shadow return value and gradient is then passed as
+    /// `width` consecutive values, one per lane, instead of a single one.
+    pub fn with_width(mut self, width: u32) -> FncInfo {
+        self.params.width = width;
+        self
+    }
+
+    /// Mark arguments whose memory might be overwritten after the primal was called,
+    /// e.g. buffers of in-place kernels. Enzyme then caches the values which it needs
+    /// for the reverse pass instead of reading them again.
+    ///
+    /// Give one entry per argument. Arguments are cacheable unless specified otherwise.
+    pub fn with_uncacheable(mut self, uncacheable: Vec<bool>) -> FncInfo {
+        self.params.uncacheable = uncacheable;
+        self
+    }
+
+    /// Tell Enzyme that the integer argument at position `arg` will only be called
+    /// with one of the given `values`, e.g. a fixed length or stride.
+    /// This allows Enzyme to specialise loops over that argument.
+    pub fn with_known_values(mut self, arg: usize, values: Vec<i64>) -> FncInfo {
+        if self.params.known_values.len() <= arg {
+            self.params.known_values.resize(arg + 1, vec![]);
+        }
+        self.params.known_values[arg] = values;
+        self
+    }
 }
 
 // The Enzyme API is too unspecific for the return type, so we introduced
@@ -88,6 +280,10 @@ impl FncInfo {
 // the ret activity. Enzyme doesn't know this type, so we match it back.
 // We should add the capability to enzyme to also support DUP_ARG on merged
 // forward+reverse however.
+// Duplicated and DuplicatedNoNeed are only valid for functions returning a pointer,
+// they return the shadow pointer (after the primal pointer for Duplicated).
+// So far they can only be used in forward mode and by the augmented primal of split mode.
+// In forward mode the same variants are used, with Gradient referring to the tangent.
 #[repr(u32)]
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
 pub enum ReturnActivity {
@@ -96,12 +292,8 @@ pub enum ReturnActivity {
     Constant,
     Ignore,
     None,
-}
-#[repr(u32)]
-#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
-pub enum FwdReturnActivity {
-    Active,
-    Gradient,
+    Duplicated,
+    DuplicatedNoNeed,
 }
 
 pub struct AutoDiff {
@@ -109,6 +301,14 @@ pub struct AutoDiff {
     type_analysis: EnzymeTypeAnalysisRef,
 }
 
+/// The two functions generated in split reverse mode
+pub struct SplitFunctions {
+    pub augmented: LLVMValueRef,
+    pub gradient: LLVMValueRef,
+    /// The type of the memory behind the tape, null if there is no tape
+    pub tape_type: LLVMTypeRef,
+}
+
 impl AutoDiff {
     pub fn new(opt: bool) -> Self {
         let logic_ref = unsafe { CreateEnzymeLogic(opt as u8) };
@@ -123,73 +323,212 @@ impl AutoDiff {
     pub fn create_fwd_diff(
         &self,
         fnc_todiff: LLVMValueRef,
-        args_activity: &mut [CDIFFE_TYPE],
-        ret_info: FwdReturnActivity,
-    ) {
+        params: &mut ParamInfos,
+        type_info: &FncTypeInfo,
+    ) -> LLVMValueRef {
+        // Forward mode has no output gradient, the tangent of the return value
+        // is handled like a duplicated argument instead.
+        let (ret_activity, ret_primary_ret) = match params.ret_info {
+            ReturnActivity::Active | ReturnActivity::Duplicated => (CDIFFE_TYPE::DFT_DUP_ARG, 1),
+            ReturnActivity::Gradient | ReturnActivity::DuplicatedNoNeed => {
+                (CDIFFE_TYPE::DFT_DUP_NONEED, 0)
+            }
+            ReturnActivity::Constant => (CDIFFE_TYPE::DFT_CONSTANT, 1),
+            ReturnActivity::Ignore => (CDIFFE_TYPE::DFT_CONSTANT, 0),
+            ReturnActivity::None => (CDIFFE_TYPE::DFT_CONSTANT, 0),
+        };
+
+        let mut args = EnzymeArgs::new(type_info, &params.uncacheable, &params.known_values);
+        let fnc_type_info = args.fnc_type_info(type_info);
+
+        unsafe {
+            EnzymeCreateForwardDiff(
+                self.logic_ref, // Logic
+                fnc_todiff,
+                ret_activity, // LLVM function, return type
+                params.input_activity.as_mut_ptr(),
+                params.input_activity.len() as u64, // constant arguments
+                self.type_analysis,                 // type analysis struct
+                ret_primary_ret,
+                CDerivativeMode::DEM_ForwardMode, // return value, mode
+                1,                                // free memory
+                params.width,                     // vector mode width
+                ptr::null_mut(),
+                fnc_type_info, // additional_arg, type info (return + args)
+                args.uncacheable.as_mut_ptr(),
+                args.uncacheable.len() as u64, // uncacheable arguments
+                ptr::null_mut(),               // augmented function, only used in split mode
+            )
+        }
     }
 
     pub fn create_primal_and_gradient(
         &self,
         fnc_todiff: LLVMValueRef,
-        args_activity: &mut [CDIFFE_TYPE],
-        ret_info: ReturnActivity,
+        params: &mut ParamInfos,
+        type_info: &FncTypeInfo,
     ) -> LLVMValueRef {
-        let (ret_activity, ret_primary_ret) = match ret_info {
-            ReturnActivity::Active => (CDIFFE_TYPE::DFT_OUT_DIFF, true as u8),
-            ReturnActivity::Gradient => (CDIFFE_TYPE::DFT_OUT_DIFF, false as u8),
-            ReturnActivity::Constant => (CDIFFE_TYPE::DFT_CONSTANT, true as u8),
-            ReturnActivity::Ignore => (CDIFFE_TYPE::DFT_CONSTANT, false as u8),
-            ReturnActivity::None => (CDIFFE_TYPE::DFT_CONSTANT, false as u8), // those should be ignored by enzyme since we don't have a return, just a safe fallback
-        };
-
-        let tree_tmp = TypeTree::new();
+        self.create_gradient(
+            fnc_todiff,
+            params,
+            type_info,
+            reverse_ret_activity(params.ret_info),
+            CDerivativeMode::DEM_ReverseModeCombined,
+            ptr::null_mut(),
+        )
+    }
 
-        let mut args_tree = vec![tree_tmp.inner; args_activity.len()];
+    pub fn create_split(
+        &self,
+        fnc_todiff: LLVMValueRef,
+        params: &mut ParamInfos,
+        type_info: &FncTypeInfo,
+    ) -> SplitFunctions {
+        let (ret_activity, ret_primary_ret, ret_shadow_ret) = reverse_ret_activity(params.ret_info);
 
-        // We don't support volatile / extern / (global?) values.
-        // Just because I didn't had time to test them, and it seems less urgent.
-        let mut args_uncacheable = vec![0; args_activity.len()];
+        let mut args = EnzymeArgs::new(type_info, &params.uncacheable, &params.known_values);
+        let fnc_type_info = args.fnc_type_info(type_info);
 
-        //let ret = tree::TypeTree::from_type(CConcreteType::DT_Float, context).prepend(0);
-        let ret = TypeTree::new();
+        let augmented = unsafe {
+            EnzymeCreateAugmentedPrimal(
+                self.logic_ref, // Logic
+                fnc_todiff,
+                ret_activity, // LLVM function, return type
+                params.input_activity.as_mut_ptr(),
+                params.input_activity.len() as u64, // constant arguments
+                self.type_analysis,                 // type analysis struct
+                ret_primary_ret as u8,
+                ret_shadow_ret as u8, // shadow return used
+                fnc_type_info,        // type info (return + args)
+                args.uncacheable.as_mut_ptr(),
+                args.uncacheable.len() as u64, // uncacheable arguments
+                1,            // force anonymous tape, so it is a pointer which Rust can declare
+                params.width, // vector mode width
+                0,            // atomic add
+            )
+        };
 
-        let kv_tmp = IntList {
-            data: ptr::null_mut(),
-            size: 0,
+        let (augmented_fnc, tape_type) = unsafe {
+            (
+                EnzymeExtractFunctionFromAugmentation(augmented),
+                EnzymeExtractTapeTypeFromAugmentation(augmented),
+            )
         };
 
-        let mut known_values = vec![kv_tmp; args_activity.len()];
+        // The primal value and the shadow are already returned by the augmented function.
+        let gradient = self.create_gradient(
+            fnc_todiff,
+            params,
+            type_info,
+            (ret_activity, false, false),
+            CDerivativeMode::DEM_ReverseModeGradient,
+            augmented,
+        );
 
-        let dummy_type = CFnTypeInfo {
-            Arguments: args_tree.as_mut_ptr(),
-            Return: ret.inner,
-            KnownValues: known_values.as_mut_ptr(),
+        SplitFunctions {
+            augmented: augmented_fnc,
+            gradient,
+            tape_type,
+        }
+    }
+
+    fn create_gradient(
+        &self,
+        fnc_todiff: LLVMValueRef,
+        params: &mut ParamInfos,
+        type_info: &FncTypeInfo,
+        (ret_activity, ret_primary_ret, ret_shadow_ret): (CDIFFE_TYPE, bool, bool),
+        mode: CDerivativeMode,
+        augmented: EnzymeAugmentedReturnPtr,
+    ) -> LLVMValueRef {
+        let mut args = EnzymeArgs::new(type_info, &params.uncacheable, &params.known_values);
+        let fnc_type_info = args.fnc_type_info(type_info);
+
+        // The reverse pass of split mode receives the tape as additional argument.
+        let tape_type = if augmented.is_null() {
+            ptr::null_mut()
+        } else {
+            unsafe { EnzymeExtractTapeTypeFromAugmentation(augmented) }
         };
 
-        dbg!("before-ad");
-        let res = unsafe {
+        unsafe {
             EnzymeCreatePrimalAndGradient(
                 self.logic_ref, // Logic
                 fnc_todiff,
                 ret_activity, // LLVM function, return type
-                args_activity.as_mut_ptr(),
-                args_activity.len() as u64, // constant arguments
-                self.type_analysis,         // type analysis struct
+                params.input_activity.as_mut_ptr(),
+                params.input_activity.len() as u64, // constant arguments
+                self.type_analysis,                 // type analysis struct
                 ret_primary_ret as u8,
-                0,                                        //0
-                CDerivativeMode::DEM_ReverseModeCombined, // return value, dret_used, top_level which was 1
-                1,                                        // vector mode width
-                1,                                        // free memory
-                ptr::null_mut(),
-                dummy_type, // additional_arg, type info (return + args)
-                args_uncacheable.as_mut_ptr(),
-                args_uncacheable.len() as u64, // uncacheable arguments
-                ptr::null_mut(),               // write augmented function to this
+                ret_shadow_ret as u8,
+                mode,         // return value, dret_used, top_level which was 1
+                params.width, // vector mode width
+                1,            // free memory
+                tape_type,
+                fnc_type_info, // additional_arg, type info (return + args)
+                args.uncacheable.as_mut_ptr(),
+                args.uncacheable.len() as u64, // uncacheable arguments
+                augmented,                     // write augmented function to this
                 0,
             )
-        };
-        dbg!("after-ad");
-        res
+        }
+    }
+}
+
+/// The per-argument arrays handed to Enzyme. They have to outlive the Enzyme call.
+struct EnzymeArgs {
+    trees: Vec<CTypeTreeRef>,
+    uncacheable: Vec<u8>,
+    known_values: Vec<IntList>,
+}
+
+impl EnzymeArgs {
+    fn new(type_info: &FncTypeInfo, uncacheable: &[bool], known_values: &[Vec<i64>]) -> Self {
+        let num_args = type_info.args.len();
+        let trees = type_info.args.iter().map(|tree| tree.inner).collect();
+        // Arguments are cacheable unless the user told us otherwise.
+        let uncacheable = (0..num_args)
+            .map(|i| uncacheable.get(i).copied().unwrap_or(false) as u8)
+            .collect();
+        // Enzyme only reads the known values, so handing out our buffers is fine.
+        let known_values = (0..num_args)
+            .map(|i| match known_values.get(i) {
+                Some(values) if !values.is_empty() => IntList {
+                    data: values.as_ptr() as *mut i64,
+                    size: values.len() as u64,
+                },
+                _ => IntList {
+                    data: ptr::null_mut(),
+                    size: 0,
+                },
+            })
+            .collect();
+        EnzymeArgs {
+            trees,
+            uncacheable,
+            known_values,
+        }
+    }
+
+    fn fnc_type_info(&mut self, type_info: &FncTypeInfo) -> CFnTypeInfo {
+        CFnTypeInfo {
+            Arguments: self.trees.as_mut_ptr(),
+            Return: type_info.ret.inner,
+            KnownValues: self.known_values.as_mut_ptr(),
+        }
+    }
+}
+
+/// The Enzyme return activity, whether the primal value and whether the shadow is returned.
+fn reverse_ret_activity(ret_info: ReturnActivity) -> (CDIFFE_TYPE, bool, bool) {
+    match ret_info {
+        ReturnActivity::Active => (CDIFFE_TYPE::DFT_OUT_DIFF, true, false),
+        ReturnActivity::Gradient => (CDIFFE_TYPE::DFT_OUT_DIFF, false, false),
+        ReturnActivity::Constant => (CDIFFE_TYPE::DFT_CONSTANT, true, false),
+        ReturnActivity::Ignore => (CDIFFE_TYPE::DFT_CONSTANT, false, false),
+        ReturnActivity::None => (CDIFFE_TYPE::DFT_CONSTANT, false, false), // those should be ignored by enzyme since we don't have a return, just a safe fallback
+        ReturnActivity::Duplicated => (CDIFFE_TYPE::DFT_DUP_ARG, true, true),
+        ReturnActivity::DuplicatedNoNeed => (CDIFFE_TYPE::DFT_DUP_NONEED, false, true),
     }
 }
 
@@ -1,7 +1,8 @@
 mod enzyme_sys;
 pub mod enzyme_wrapper;
-mod tree;
+pub(crate) mod tree;
 
 pub use enzyme_wrapper::{enzyme_print_activity, enzyme_print_functions, enzyme_print_type};
-pub use enzyme_wrapper::{AutoDiff, FncInfo, ParamInfos};
-pub use enzyme_wrapper::{LLVMOpaqueValue, ReturnActivity, CDIFFE_TYPE};
+pub use enzyme_wrapper::{AutoDiff, DiffMode, FncInfo, ParamInfos};
+pub use enzyme_wrapper::{CConcreteType, LLVMOpaqueValue, ReturnActivity, CDIFFE_TYPE};
+pub use tree::{FncTypeInfo, TypeTree};
@@ -1,14 +1,239 @@
-use super::enzyme_sys::{CTypeTreeRef, EnzymeFreeTypeTree, EnzymeNewTypeTree};
+use super::enzyme_sys::{
+    CConcreteType, CTypeTreeRef, EnzymeFreeTypeTree, EnzymeMergeTypeTree, EnzymeNewTypeTree,
+    EnzymeNewTypeTreeCT, EnzymeNewTypeTreeTR, EnzymeTypeTreeOnlyEq, EnzymeTypeTreeShiftIndiciesEq,
+    EnzymeTypeTreeToString, EnzymeTypeTreeToStringFree,
+};
+
+use llvm_sys::core::*;
+use llvm_sys::prelude::*;
+use llvm_sys::target::{
+    LLVMABISizeOfType, LLVMCopyStringRepOfTargetData, LLVMOffsetOfElement, LLVMTargetDataRef,
+};
+use llvm_sys::LLVMTypeKind;
+
+use std::ffi::{CStr, CString};
+use std::fmt;
+
+// We only follow pointers that far, recursive types would otherwise never terminate.
+pub(crate) const MAX_POINTER_DEPTH: u32 = 4;
+// Larger arrays are described by their first elements, which is enough for Enzyme.
+pub(crate) const MAX_ARRAY_ELEMENTS: u64 = 64;
 
 pub struct TypeTree {
     pub inner: CTypeTreeRef,
+    // Enzyme can't tell us, so we track whether any type was added to the tree.
+    empty: bool,
 }
 
 impl TypeTree {
     pub fn new() -> TypeTree {
         let inner = unsafe { EnzymeNewTypeTree() };
 
-        TypeTree { inner }
+        TypeTree { inner, empty: true }
+    }
+
+    /// A tree which only holds the given type, without any offset
+    pub fn from_type(t: CConcreteType, ctx: LLVMContextRef) -> TypeTree {
+        let inner = unsafe { EnzymeNewTypeTreeCT(t, ctx as _) };
+
+        TypeTree {
+            inner,
+            empty: false,
+        }
+    }
+
+    /// Prepend the offset to all entries, e.g. {[]:Double}.only(8) becomes {[8]:Double}.
+    /// Use -1 for "any offset" or for the value of an argument itself.
+    pub fn only(self, offset: isize) -> TypeTree {
+        unsafe { EnzymeTypeTreeOnlyEq(self.inner, offset as i64) };
+        self
+    }
+
+    /// Whether the tree holds no types. `shift` doesn't change it, since we only move
+    /// entries within the given size.
+    pub fn is_empty(&self) -> bool {
+        self.empty
+    }
+
+    /// Add all entries of the other tree. Returns the combined tree.
+    pub fn merge(mut self, other: Self) -> TypeTree {
+        unsafe { EnzymeMergeTypeTree(self.inner, other.inner) };
+        self.empty &= other.empty;
+        drop(other);
+        self
+    }
+
+    /// Move all entries starting at `offset` (and within `max_size` bytes)
+    /// by `add_offset` bytes.
+    pub fn shift(self, layout: &str, offset: isize, max_size: isize, add_offset: usize) -> Self {
+        let layout = CString::new(layout).unwrap();
+        unsafe {
+            EnzymeTypeTreeShiftIndiciesEq(
+                self.inner,
+                layout.as_ptr(),
+                offset as i64,
+                max_size as i64,
+                add_offset as u64,
+            )
+        };
+        self
+    }
+
+    /// The tree of an argument or return value of the given LLVM type.
+    ///
+    /// Scalars and pointers describe the value itself at offset -1, pointers additionally
+    /// describe the memory they point to. Aggregates describe their memory by offsets.
+    pub fn from_llvm_type(ty: LLVMTypeRef, td: LLVMTargetDataRef, ctx: LLVMContextRef) -> Self {
+        unsafe {
+            match LLVMGetTypeKind(ty) {
+                LLVMTypeKind::LLVMStructTypeKind | LLVMTypeKind::LLVMArrayTypeKind => {
+                    memory_tree(ty, td, ctx, 0)
+                }
+                LLVMTypeKind::LLVMPointerTypeKind => {
+                    TypeTree::from_type(CConcreteType::DT_Pointer, ctx)
+                        .merge(pointee_tree(ty, td, ctx, 0))
+                        .only(-1)
+                }
+                _ => match concrete_type(ty) {
+                    Some(t) => TypeTree::from_type(t, ctx).only(-1),
+                    None => TypeTree::new(),
+                },
+            }
+        }
+    }
+}
+
+/// The Enzyme type for scalars, None if we don't know (e.g. void or vectors).
+/// Integers are left to Enzyme, since Rust passes small float aggregates like `[f32; 2]`
+/// as integers. Marking those as DT_Integer would make their gradients silently zero.
+unsafe fn concrete_type(ty: LLVMTypeRef) -> Option<CConcreteType> {
+    match LLVMGetTypeKind(ty) {
+        LLVMTypeKind::LLVMHalfTypeKind => Some(CConcreteType::DT_Half),
+        LLVMTypeKind::LLVMFloatTypeKind => Some(CConcreteType::DT_Float),
+        LLVMTypeKind::LLVMDoubleTypeKind => Some(CConcreteType::DT_Double),
+        LLVMTypeKind::LLVMPointerTypeKind => Some(CConcreteType::DT_Pointer),
+        _ => None,
+    }
+}
+
+/// The tree describing the memory pointed to.
+/// Scalar pointees are assumed to be arrays (Vec, slices, ...), so they are valid at any offset.
+unsafe fn pointee_tree(
+    ptr: LLVMTypeRef,
+    td: LLVMTargetDataRef,
+    ctx: LLVMContextRef,
+    depth: u32,
+) -> TypeTree {
+    let pointee = LLVMGetElementType(ptr);
+    if depth >= MAX_POINTER_DEPTH || LLVMTypeIsSized(pointee) == 0 {
+        return TypeTree::new();
+    }
+    match LLVMGetTypeKind(pointee) {
+        LLVMTypeKind::LLVMStructTypeKind | LLVMTypeKind::LLVMArrayTypeKind => {
+            memory_tree(pointee, td, ctx, depth + 1)
+        }
+        LLVMTypeKind::LLVMPointerTypeKind => TypeTree::from_type(CConcreteType::DT_Pointer, ctx)
+            .merge(pointee_tree(pointee, td, ctx, depth + 1))
+            .only(-1),
+        _ => match concrete_type(pointee) {
+            Some(t) => TypeTree::from_type(t, ctx).only(-1),
+            None => TypeTree::new(),
+        },
+    }
+}
+
+/// The tree describing memory of the given type, starting at offset 0.
+unsafe fn memory_tree(
+    ty: LLVMTypeRef,
+    td: LLVMTargetDataRef,
+    ctx: LLVMContextRef,
+    depth: u32,
+) -> TypeTree {
+    match LLVMGetTypeKind(ty) {
+        LLVMTypeKind::LLVMStructTypeKind => {
+            let num_elements = LLVMCountStructElementTypes(ty);
+            let mut elements: Vec<LLVMTypeRef> = Vec::with_capacity(num_elements as usize);
+            LLVMGetStructElementTypes(ty, elements.as_mut_ptr());
+            elements.set_len(num_elements as usize);
+            let layout = data_layout(td);
+            let mut tree = TypeTree::new();
+            for (i, element) in elements.into_iter().enumerate() {
+                let element_size = LLVMABISizeOfType(td, element) as isize;
+                let element_offset = LLVMOffsetOfElement(td, ty, i as u32) as usize;
+                let element_tree = memory_tree(element, td, ctx, depth).shift(
+                    &layout,
+                    0,
+                    element_size,
+                    element_offset,
+                );
+                tree = tree.merge(element_tree);
+            }
+            tree
+        }
+        LLVMTypeKind::LLVMArrayTypeKind => {
+            let element = LLVMGetElementType(ty);
+            let element_size = LLVMABISizeOfType(td, element);
+            let layout = data_layout(td);
+            let element_tree = memory_tree(element, td, ctx, depth);
+            let mut tree = TypeTree::new();
+            for i in 0..(LLVMGetArrayLength(ty) as u64).min(MAX_ARRAY_ELEMENTS) {
+                let element_offset = (i * element_size) as usize;
+                tree = tree.merge(element_tree.clone().shift(
+                    &layout,
+                    0,
+                    element_size as isize,
+                    element_offset,
+                ));
+            }
+            tree
+        }
+        LLVMTypeKind::LLVMPointerTypeKind => TypeTree::from_type(CConcreteType::DT_Pointer, ctx)
+            .merge(pointee_tree(ty, td, ctx, depth))
+            .only(0),
+        _ => match concrete_type(ty) {
+            Some(t) => TypeTree::from_type(t, ctx).only(0),
+            None => TypeTree::new(),
+        },
+    }
+}
+
+unsafe fn data_layout(td: LLVMTargetDataRef) -> String {
+    let layout = LLVMCopyStringRepOfTargetData(td);
+    let res = CStr::from_ptr(layout).to_string_lossy().into_owned();
+    LLVMDisposeMessage(layout);
+    res
+}
+
+impl Clone for TypeTree {
+    fn clone(&self) -> Self {
+        let inner = unsafe { EnzymeNewTypeTreeTR(self.inner) };
+
+        TypeTree {
+            inner,
+            empty: self.empty,
+        }
+    }
+}
+
+impl fmt::Display for TypeTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ptr = unsafe { EnzymeTypeTreeToString(self.inner) };
+        let cstr = unsafe { CStr::from_ptr(ptr) };
+        let res = match cstr.to_str() {
+            Ok(x) => write!(f, "{}", x),
+            Err(err) => write!(f, "could not parse: {}", err),
+        };
+
+        // delete C string pointer
+        unsafe { EnzymeTypeTreeToStringFree(ptr) };
+
+        res
+    }
+}
+
+impl fmt::Debug for TypeTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        <Self as fmt::Display>::fmt(self, f)
     }
 }
 
@@ -18,4 +243,47 @@ impl Drop for TypeTree {
     }
 }
 
-//dwarf metadata in llvm-ir und konvertieren in type_tree: https://docs.rs/llvm-sys/110.0.1/llvm_sys/debuginfo/index.html
+/// The type trees of all arguments and of the return value of one function
+#[derive(Clone, Debug)]
+pub struct FncTypeInfo {
+    pub args: Vec<TypeTree>,
+    pub ret: TypeTree,
+}
+
+impl FncTypeInfo {
+    /// Build the trees based on the LLVM types of the function's parameters and return value.
+    ///
+    /// # Safety
+    ///
+    /// `fnc` must be a valid function, which is part of a module.
+    pub unsafe fn from_llvm_function(fnc: LLVMValueRef) -> FncTypeInfo {
+        let module = LLVMGetGlobalParent(fnc);
+        let ctx = LLVMGetModuleContext(module);
+        let td = llvm_sys::target::LLVMGetModuleDataLayout(module);
+
+        let num_params = LLVMCountParams(fnc) as usize;
+        let mut params: Vec<LLVMValueRef> = Vec::with_capacity(num_params);
+        LLVMGetParams(fnc, params.as_mut_ptr());
+        params.set_len(num_params);
+
+        let args = params
+            .into_iter()
+            .map(|param| TypeTree::from_llvm_type(LLVMTypeOf(param), td, ctx))
+            .collect();
+        let ret_type = LLVMGetReturnType(LLVMGetElementType(LLVMTypeOf(fnc)));
+        let ret = TypeTree::from_llvm_type(ret_type, td, ctx);
+
+        FncTypeInfo { args, ret }
+    }
+
+    /// Replace the trees of the arguments by more detailed ones, e.g. based on debug info.
+    /// Merging them instead could give Enzyme conflicting types for the same offset.
+    /// Empty trees keep the old tree of their argument.
+    pub fn replace_args(&mut self, trees: Vec<TypeTree>) {
+        for (arg, tree) in self.args.iter_mut().zip(trees) {
+            if !tree.is_empty() {
+                *arg = tree;
+            }
+        }
+    }
+}
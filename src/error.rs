@@ -0,0 +1,184 @@
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Everything which can go wrong while generating the gradient functions
+#[derive(Debug)]
+pub enum EnzymeBuildError {
+    /// Cargo didn't set an environment variable which we rely on.
+    MissingEnv(String),
+    /// A configuration value like the opt-level or code model which we can't use.
+    InvalidConfig { option: String, message: String },
+    /// An external tool like cargo or ar failed.
+    ToolFailed {
+        command: String,
+        status: Option<i32>,
+        output: String,
+    },
+    /// We couldn't find the bitcode of a crate.
+    MissingBitcode { crate_name: String, dir: PathBuf },
+    /// LLVM couldn't read or parse a bitcode file.
+    InvalidBitcode { path: PathBuf, message: String },
+    /// The function which should be differentiated isn't part of the bitcode.
+    /// For Rust paths we list similar ones which we found.
+    MissingFunction {
+        name: String,
+        near_matches: Vec<String>,
+    },
+    /// The Rust path matches more than one function, e.g. instances of a generic function.
+    AmbiguousFunction { name: String, symbols: Vec<String> },
+    /// A function or symbol name which we can't hand to LLVM, since it contains a NUL byte.
+    InvalidName(String),
+    /// The FncInfo doesn't fit the function which it describes.
+    InvalidFncInfo { function: String, message: String },
+    /// Some higher-order derivatives depend on each other in a cycle.
+    DependencyCycle(Vec<String>),
+    /// The crate has no extern declaration for a generated function.
+    MissingDeclaration(String),
+    /// The generated function doesn't match its extern declaration, and we don't know
+    /// how to convert between them.
+    TypeMismatch {
+        function: String,
+        declared: String,
+        generated: String,
+    },
+    /// We can't declare a generated function in Rust, since it uses the given LLVM type.
+    UnsupportedType { function: String, ty: String },
+    /// We failed to build a wrapper which matches the extern declaration.
+    WrapperFailed { function: String, message: String },
+    /// LLVM considers the module to be broken after the given stage.
+    InvalidModule { stage: String, message: String },
+    /// LLVM couldn't parse or run the given pass pipeline.
+    PassesFailed { pipeline: String, message: String },
+    /// Creating the target machine or emitting the object file failed.
+    Codegen(String),
+    /// Reading or writing one of our files failed.
+    Io { path: PathBuf, message: String },
+    /// Something which should never happen. Please report it!
+    Internal(String),
+}
+
+impl fmt::Display for EnzymeBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnzymeBuildError::MissingEnv(name) => write!(
+                f,
+                "The environment variable {} is not set. Please call oxide_enzyme from your build.rs!",
+                name
+            ),
+            EnzymeBuildError::InvalidConfig { option, message } => {
+                write!(f, "Invalid {}! {}", option, message)
+            }
+            EnzymeBuildError::ToolFailed {
+                command,
+                status,
+                output,
+            } => {
+                write!(f, "Failed: `{}`", command)?;
+                if let Some(status) = status {
+                    write!(f, " (exit code {})", status)?;
+                }
+                write!(f, "\n{}", output)
+            }
+            EnzymeBuildError::MissingBitcode { crate_name, dir } => write!(
+                f,
+                "Couldn't find the bc file of {} in {}. Is it a dependency of your crate?",
+                crate_name,
+                dir.display()
+            ),
+            EnzymeBuildError::InvalidBitcode { path, message } => {
+                write!(f, "Could not read {}: {}", path.display(), message)
+            }
+            EnzymeBuildError::MissingFunction { name, near_matches } => {
+                write!(
+                    f,
+                    "We couldn't find the function definition for {}. Please add it. \
+                     Generic functions are only compiled for the types which your crate uses them with, \
+                     and #[inline] functions of other crates aren't part of the bitcode of their crate.",
+                    name
+                )?;
+                if !near_matches.is_empty() {
+                    write!(f, " Did you mean one of {:?}?", near_matches)?;
+                }
+                Ok(())
+            }
+            EnzymeBuildError::AmbiguousFunction { name, symbols } => write!(
+                f,
+                "The path {} matches several functions, please add the generic arguments \
+                 or give one of their symbols instead: {:?}",
+                name, symbols
+            ),
+            EnzymeBuildError::InvalidName(name) => write!(
+                f,
+                "The name {} contains a NUL byte, which LLVM doesn't support.",
+                name.replace('\0', "\\0")
+            ),
+            EnzymeBuildError::InvalidFncInfo { function, message } => write!(
+                f,
+                "The function {} does not work with the FncInfo which you gave! {}",
+                function, message
+            ),
+            EnzymeBuildError::DependencyCycle(names) => write!(
+                f,
+                "The gradients {:?} depend on each other in a cycle. \
+                 Please double-check your build.rs file.",
+                names
+            ),
+            EnzymeBuildError::MissingDeclaration(name) => write!(
+                f,
+                "Couldn't get undef symbol for {}. \
+                 Do you include the generated gradients.rs and call the function?",
+                name
+            ),
+            EnzymeBuildError::TypeMismatch {
+                function,
+                declared,
+                generated,
+            } => write!(
+                f,
+                "The declaration of {} has the type {}, but we generated {}. \
+                 Please check your extern block and FncInfo.",
+                function, declared, generated
+            ),
+            EnzymeBuildError::UnsupportedType { function, ty } => write!(
+                f,
+                "We can't generate a Rust declaration for {}, it uses the type {}.",
+                function, ty
+            ),
+            EnzymeBuildError::WrapperFailed { function, message } => write!(
+                f,
+                "Creating a wrapper function for {} failed! {}",
+                function, message
+            ),
+            EnzymeBuildError::InvalidModule { stage, message } => {
+                write!(f, "The module is broken {}! {}", stage, message)
+            }
+            EnzymeBuildError::PassesFailed { pipeline, message } => {
+                write!(f, "Running the passes `{}` failed! {}", pipeline, message)
+            }
+            EnzymeBuildError::Codegen(message) => {
+                write!(f, "Generating the object file failed! {}", message)
+            }
+            EnzymeBuildError::Io { path, message } => {
+                write!(f, "Could not access {}: {}", path.display(), message)
+            }
+            EnzymeBuildError::Internal(message) => {
+                write!(f, "{} This is a bug, please report it!", message)
+            }
+        }
+    }
+}
+
+impl EnzymeBuildError {
+    /// Errors which can be caused by crate bitcode compiled with outdated declarations.
+    pub(crate) fn is_declaration_mismatch(&self) -> bool {
+        matches!(
+            self,
+            EnzymeBuildError::MissingDeclaration(_)
+                | EnzymeBuildError::TypeMismatch { .. }
+                | EnzymeBuildError::WrapperFailed { .. }
+        )
+    }
+}
+
+impl Error for EnzymeBuildError {}
@@ -1,116 +1,149 @@
+use std::collections::HashMap;
 use std::ffi::{CStr, CString};
+use std::fs;
 use std::path::{Path, PathBuf};
-use std::{env, fs, ptr};
+use std::{env, ptr};
 
 use llvm_sys::core::*;
 use llvm_sys::ir_reader::LLVMParseIRInContext;
 use llvm_sys::prelude::*;
 use llvm_sys::target::*;
 use llvm_sys::target_machine::*;
-use llvm_sys::LLVMLinkage;
+use llvm_sys::{LLVMLinkage, LLVMTypeKind};
 
 use glob::glob;
 use std::process::Command;
 
 pub use autodiff::differentiate_ext as differentiate;
 
+#[doc(hidden)]
+mod bindings;
+#[doc(hidden)]
+mod cache;
+mod config;
+#[doc(hidden)]
+mod dump;
+#[doc(hidden)]
+mod dwarf;
 #[doc(hidden)]
 mod enzyme;
+mod error;
+#[doc(hidden)]
+mod link;
+#[doc(hidden)]
+mod localize;
+mod passes;
+#[doc(hidden)]
+mod pattern;
+#[doc(hidden)]
+mod resolve;
+mod target;
 #[doc(hidden)]
 mod verify;
 #[doc(hidden)]
 mod wrappers;
+use config::Config;
+pub use config::{CodeModel, EnzymeConfig, OptLevel, OutputKind, RelocMode};
 pub use enzyme::{enzyme_print_activity, enzyme_print_functions, enzyme_print_type};
-use enzyme::{AutoDiff, LLVMOpaqueValue, ParamInfos};
-pub use enzyme::{FncInfo, ReturnActivity, CDIFFE_TYPE};
-
-fn llvm_bin_dir() -> PathBuf {
-    let rustc_ver = env!("RUSTC_VER");
-    let target = env!("TARGET");
-    dirs::cache_dir()
-        .unwrap()
-        .join("enzyme")
-        .join("rustc-".to_owned() + rustc_ver + "-src")
-        .join("build")
-        .join(target)
-        .join("llvm")
-        .join("build")
-        .join("bin")
+use enzyme::{AutoDiff, FncTypeInfo, LLVMOpaqueValue};
+pub use enzyme::{DiffMode, FncInfo, ParamInfos, ReturnActivity, CDIFFE_TYPE};
+pub use error::EnzymeBuildError;
+pub use localize::DEFAULT_LOCAL_SYMBOLS;
+pub use passes::PassPipeline;
+pub use target::TargetOptions;
+
+fn env_var(name: &str) -> Result<String, EnzymeBuildError> {
+    env::var(name).map_err(|_| EnzymeBuildError::MissingEnv(name.to_owned()))
 }
 
-fn llvm_objcopy() -> PathBuf {
-    llvm_bin_dir().join("llvm-objcopy")
+/// The C string of a function or symbol name which we hand to LLVM.
+pub(crate) fn c_name(name: &str) -> Result<CString, EnzymeBuildError> {
+    CString::new(name).map_err(|_| EnzymeBuildError::InvalidName(name.to_owned()))
 }
-fn llvm_link() -> PathBuf {
-    llvm_bin_dir().join("llvm-link")
+
+/// LLVM and glob only accept paths which are valid UTF-8.
+pub(crate) fn path_str(path: &Path) -> Result<&str, EnzymeBuildError> {
+    path.to_str().ok_or_else(|| EnzymeBuildError::Io {
+        path: path.to_owned(),
+        message: "The path is not valid UTF-8.".to_owned(),
+    })
 }
 
-fn run_and_printerror(command: &mut Command) {
+/// The C string of a path which we hand to LLVM.
+pub(crate) fn c_path(path: &Path) -> Result<CString, EnzymeBuildError> {
+    CString::new(path_str(path)?).map_err(|_| EnzymeBuildError::Io {
+        path: path.to_owned(),
+        message: "The path contains a NUL byte.".to_owned(),
+    })
+}
+
+fn run_and_printerror(command: &mut Command) -> Result<(), EnzymeBuildError> {
     println!("Running: `{:?}`", command);
-    match command.status() {
-        Ok(status) => {
-            if !status.success() {
-                panic!("Failed: `{:?}` ({})", command, status);
-            }
-        }
-        Err(error) => {
-            panic!("Failed: `{:?}` ({})", command, error);
-        }
+    let output = command
+        .output()
+        .map_err(|error| EnzymeBuildError::ToolFailed {
+            command: format!("{:?}", command),
+            status: None,
+            output: error.to_string(),
+        })?;
+    // Don't print it to stdout, cargo would interpret it.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    eprint!("{}{}", stdout, stderr);
+    if !output.status.success() {
+        return Err(EnzymeBuildError::ToolFailed {
+            command: format!("{:?}", command),
+            status: output.status.code(),
+            output: stderr.into_owned(),
+        });
     }
+    Ok(())
 }
 
-/// Create target machine with default relocation/optimization/code model
-fn create_target_machine() -> LLVMTargetMachineRef {
-    let (triple, cpu, feature) = unsafe {
-        LLVM_InitializeNativeTarget(); //needed for GetDefaultTargetTriple()
-
+/// Create target machine with the configured target and relocation/optimization/code model
+fn create_target_machine(config: &Config) -> Result<LLVMTargetMachineRef, EnzymeBuildError> {
+    let target_info = &config.target;
+    unsafe {
         LLVM_InitializeAllTargetInfos();
         LLVM_InitializeAllTargets();
         LLVM_InitializeAllTargetMCs();
         LLVM_InitializeAllAsmParsers();
         LLVM_InitializeAllAsmPrinters();
+    }
+    let triple = target_info.triple.as_ptr();
+    let cpu = target_info.cpu.as_ptr();
+    let feature = target_info.features.as_ptr();
 
-        let triple = LLVMGetDefaultTargetTriple();
-        let cpu = LLVMGetHostCPUName();
-        let feature = LLVMGetHostCPUFeatures();
-        (triple, cpu, feature)
-    };
-
-    let opt_level = if cfg!(debug_assertions) {
-        LLVMCodeGenOptLevel::LLVMCodeGenLevelNone
-    } else {
-        LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive
-    };
-
-    let reloc_mode = LLVMRelocMode::LLVMRelocPIC;
-
+    let opt_level = config.opt_level.to_llvm();
+    let reloc_mode = config.reloc_mode.to_llvm();
     // https://doc.rust-lang.org/rustc/codegen-options/index.html#code-model
-    let code_model = LLVMCodeModel::LLVMCodeModelSmall;
-
-    unsafe {
-        dbg!("CPU:", CStr::from_ptr(cpu).to_str().unwrap());
-        dbg!("Triple:", CStr::from_ptr(triple).to_str().unwrap());
-        dbg!("Feature:", CStr::from_ptr(feature).to_str().unwrap());
-    }
+    let code_model = config.code_model.to_llvm();
 
     let mut target = ptr::null_mut();
     let mut msg = ptr::null_mut();
 
     unsafe {
-        assert!(
-            LLVMGetTargetFromTriple(triple, &mut target, &mut msg) == 0,
-            "Could not get target machine from triple! {:?}",
-            CStr::from_ptr(msg).to_str().unwrap()
-        );
+        if LLVMGetTargetFromTriple(triple, &mut target, &mut msg) != 0 {
+            let error_msg = format!(
+                "Could not get target machine from triple {:?}! {}",
+                target_info.triple,
+                CStr::from_ptr(msg).to_string_lossy()
+            );
+            LLVMDisposeMessage(msg);
+            return Err(EnzymeBuildError::Codegen(error_msg));
+        }
 
         // get target machine
         let target_machine = LLVMCreateTargetMachine(
             target, triple, cpu, feature, opt_level, reloc_mode, code_model,
         );
-        assert!(!target_machine.is_null(), "target_machine is null!");
-        LLVMDisposeMessage(msg);
+        if target_machine.is_null() {
+            return Err(EnzymeBuildError::Codegen(
+                "target_machine is null!".to_owned(),
+            ));
+        }
 
-        target_machine
+        Ok(target_machine)
     }
 }
 
@@ -118,163 +151,318 @@ pub(crate) fn get_type(t: LLVMTypeRef) -> CString {
     unsafe { CString::from_raw(LLVMPrintTypeToString(t)) }
 }
 
-/// Read the binary representation of LLVM IR code into a module and context
-fn read_bc_files(fnc_names: Vec<String>) -> (LLVMModuleRef, LLVMContextRef) {
-    // Collect some environment information
-    let crate_name: String = env::var("CARGO_PKG_NAME").unwrap();
-    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
-
-    let merged_bc = out_dir
-        .join("merged.bc")
-        .into_os_string()
-        .into_string()
-        .unwrap();
-
-    let central_dir = out_dir
-        .parent()
-        .unwrap()
-        .parent()
-        .unwrap()
-        .parent()
-        .unwrap();
-    let deps_dir = central_dir.join("deps");
-
-    let mut bc_files: Vec<String> = vec![];
-    let mut main_bc: String = "".to_owned();
+// Set for the nested cargo call, so the build script of the crate knows that it should
+// only let cargo compile the bitcode, instead of compiling it once more.
+const BITCODE_PASS_ENV: &str = "ENZYME_BITCODE_PASS";
+
+/// Compile the crate which is currently built (and all its dependencies) to LLVM bitcode,
+/// using a nested cargo call with its own target dir. Returns the dir holding the bc files.
+fn compile_bitcode() -> Result<PathBuf, EnzymeBuildError> {
+    let out_dir = PathBuf::from(env_var("OUT_DIR")?);
+    let manifest_dir = PathBuf::from(env_var("CARGO_MANIFEST_DIR")?);
+    let target = env_var("TARGET")?;
+    let profile = env_var("PROFILE")?;
+    let target_dir = out_dir.join("enzyme-target");
+
+    // Cargo tells us about the enabled features only through the environment, with their
+    // names in upper case and '-' replaced by '_'. We assume the usual kebab-case names.
+    let features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            let feature = key.strip_prefix("CARGO_FEATURE_")?;
+            Some(feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+
+    let mut cargo = Command::new(env_var("CARGO")?);
+    cargo.current_dir(&manifest_dir);
+    cargo.env(BITCODE_PASS_ENV, "1");
+    cargo.env(
+        bindings::GRADIENTS_ENV,
+        out_dir.join(bindings::GRADIENTS_FILE),
+    );
+    // We also want the bitcode of all dependencies.
+    // v0 mangling keeps the generic arguments of instances, so we can find e.g. `loss::<f32>`.
+    // The flags of the user are kept, cargo passes them to us separated by 0x1f.
+    let mut rustflags = env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+    for flag in ["--emit=llvm-bc", "-Zsymbol-mangling-version=v0"] {
+        if !rustflags.is_empty() {
+            rustflags.push('\x1f');
+        }
+        rustflags.push_str(flag);
+    }
+    cargo.env("CARGO_ENCODED_RUSTFLAGS", rustflags);
+    cargo.env_remove("RUSTFLAGS");
+    cargo.args(["-Z", "build-std", "rustc", "-p"]);
+    cargo.arg(env_var("CARGO_PKG_NAME")?);
+    cargo
+        .arg("--manifest-path")
+        .arg(manifest_dir.join("Cargo.toml"));
+    cargo.arg("--target-dir").arg(&target_dir);
+    cargo.args(["--target", &target]);
+    if profile == "release" {
+        cargo.arg("--release");
+    }
+    cargo.arg("--no-default-features");
+    if !features.is_empty() {
+        cargo.args(["--features", &features.join(",")]);
+    }
+    cargo.args(["--", "--emit=llvm-bc", "-g", "-Zno-link"]);
+    run_and_printerror(&mut cargo)?;
+
+    Ok(target_dir.join(target).join(profile).join("deps"))
+}
+
+/// Find the bc files in `deps_dir`. The first list holds the bc files of our crate and of the
+/// crates defining some of the functions to differentiate, the second one all other bc files.
+fn find_bc_files(
+    deps_dir: &Path,
+    fnc_names: &[String],
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>), EnzymeBuildError> {
+    let crate_name: String = env_var("CARGO_PKG_NAME")?.replace('-', "_");
+
+    // Crates (other than ours) which define some of the functions to differentiate.
+    // Patterns can't select the crate with a glob, so we skip those.
+    let mut dep_crates: Vec<String> = fnc_names
+        .iter()
+        .filter_map(|name| resolve::crate_of(name))
+        .filter(|&krate| krate != crate_name)
+        .filter(|krate| krate.chars().all(|c| c.is_alphanumeric() || c == '_'))
+        .map(|krate| krate.to_owned())
+        .collect();
+    dep_crates.sort();
+    dep_crates.dedup();
+
+    let mut bc_files: Vec<PathBuf> = vec![];
+    let mut dep_bc_files: Vec<PathBuf> = vec![];
+    let mut main_bc: Option<PathBuf> = None;
     let search_term = deps_dir.join("*.bc");
-    let search_results = glob(search_term.to_str().unwrap())
-        .expect("Failed to read glob pattern. Please report this!");
+    let search_results = glob(path_str(&search_term)?).map_err(|e| EnzymeBuildError::Io {
+        path: search_term.clone(),
+        message: e.to_string(),
+    })?;
+    let is_bc_of = |bc: &Path, krate: &str| {
+        bc.file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| name.starts_with(&(krate.to_owned() + "-")))
+    };
     for path in search_results.flatten() {
-        let bc_string_name = path.into_os_string().into_string().unwrap();
-        if bc_string_name.starts_with(deps_dir.join(&crate_name).to_str().unwrap()) {
-            main_bc = bc_string_name;
+        if is_bc_of(&path, &crate_name) {
+            main_bc = Some(path);
+        } else if dep_crates.iter().any(|dep| is_bc_of(&path, dep)) {
+            dep_bc_files.push(path);
         } else {
-            bc_files.push(bc_string_name);
+            bc_files.push(path);
         }
     }
-    dbg!(&bc_files);
-    assert_ne!(
-        "", main_bc,
-        "Couldn't find central bc file. Please report this!"
-    );
-
-    let mut merge = Command::new(&llvm_link());
-    merge.current_dir(&central_dir);
-    for fnc in fnc_names {
-        merge.args(&["--import", &fnc, &main_bc]);
-    }
-    for bc in bc_files {
-        merge.arg(&bc);
+    let main_bc = match main_bc {
+        Some(main_bc) => main_bc,
+        None => {
+            return Err(EnzymeBuildError::MissingBitcode {
+                crate_name,
+                dir: deps_dir.to_owned(),
+            })
+        }
+    };
+    for dep in &dep_crates {
+        if !dep_bc_files.iter().any(|bc| is_bc_of(bc, dep)) {
+            return Err(EnzymeBuildError::MissingBitcode {
+                crate_name: dep.clone(),
+                dir: deps_dir.to_owned(),
+            });
+        }
     }
-    merge.args(&["--only-needed", "-o", &merged_bc]);
-    run_and_printerror(&mut merge);
 
+    // Our crate and the dependencies defining functions to differentiate have to provide them,
+    // everything else (e.g. std) is only linked where needed.
+    let mut crate_bc_files = vec![main_bc];
+    crate_bc_files.extend(dep_bc_files);
+    Ok((crate_bc_files, bc_files))
+}
+
+/// Link the bitcode files of our crate and its dependencies into a single bc file.
+/// Returns the path of the merged file.
+fn link_bc_files(
+    crate_bc_files: &[PathBuf],
+    other_bc_files: &[PathBuf],
+    fnc_names: Vec<String>,
+) -> Result<PathBuf, EnzymeBuildError> {
+    let out_dir = PathBuf::from(env_var("OUT_DIR")?);
+
+    let merged_bc = out_dir.join("merged.bc");
+    link::link_bitcode(crate_bc_files, other_bc_files, &fnc_names, &merged_bc)?;
+    Ok(merged_bc)
+}
+
+/// Read the binary representation of LLVM IR code into a module and context
+fn read_bc_file(merged_bc: &Path) -> Result<(LLVMModuleRef, LLVMContextRef), EnzymeBuildError> {
+    let invalid_bitcode = |msg: *mut std::os::raw::c_char| {
+        let message = if msg.is_null() {
+            "unknown error".to_owned()
+        } else {
+            let message = unsafe { CStr::from_ptr(msg) }
+                .to_string_lossy()
+                .into_owned();
+            unsafe { LLVMDisposeMessage(msg) };
+            message
+        };
+        EnzymeBuildError::InvalidBitcode {
+            path: merged_bc.to_owned(),
+            message,
+        }
+    };
+    let path = c_path(merged_bc)?;
     unsafe {
         let context = LLVMContextCreate();
         let mut msg = ptr::null_mut();
 
-        let path = CString::new(merged_bc).unwrap();
         let mut memory_buf = ptr::null_mut();
-        assert_eq!(
-            LLVMCreateMemoryBufferWithContentsOfFile(path.as_ptr(), &mut memory_buf, &mut msg),
-            0,
-            "Could not read LLVM-bc file. Please report this!"
-        );
+        if LLVMCreateMemoryBufferWithContentsOfFile(path.as_ptr(), &mut memory_buf, &mut msg) != 0 {
+            return Err(invalid_bitcode(msg));
+        }
 
         let mut module = ptr::null_mut();
 
-        assert!(
-            LLVMParseIRInContext(context, memory_buf, &mut module, &mut msg) == 0,
-            "Could not create the module. Please report this!"
-        );
-        LLVMDisposeMessage(msg);
+        if LLVMParseIRInContext(context, memory_buf, &mut module, &mut msg) != 0 {
+            return Err(invalid_bitcode(msg));
+        }
 
-        if let Err(e) = verify::verify_module(module) {
-            panic!(
-                "The input module was already broken. Please report this! {}",
-                e
-            );
+        if let Err(message) = verify::verify_module(module) {
+            return Err(EnzymeBuildError::InvalidModule {
+                stage: "after reading the bitcode".to_owned(),
+                message,
+            });
         }
 
-        (module, context)
+        Ok((module, context))
     }
 }
 
-fn load_primary_functions(module: LLVMModuleRef, fnc_names: Vec<String>) -> Vec<LLVMValueRef> {
+/// Look up the primary functions in the module.
+///
+/// Primary functions which are themselves generated (higher-order derivatives) don't exist yet,
+/// so we just store a null placeholder for them.
+fn load_primary_functions(
+    module: LLVMModuleRef,
+    fnc_names: Vec<String>,
+    generated_names: &[String],
+) -> Result<Vec<LLVMValueRef>, EnzymeBuildError> {
     let mut functions: Vec<LLVMValueRef> = vec![];
     for fnc_name in &fnc_names {
-        let c_name = CString::new(fnc_name.clone()).unwrap();
-        let llvm_fnc = unsafe { LLVMGetNamedFunction(module, c_name.as_ptr()) };
-        assert_ne!(
-            llvm_fnc as usize, 0,
-            "We couldn't find the function definition for {}. Please add it.",
-            fnc_name
-        );
+        if generated_names.contains(fnc_name) {
+            functions.push(ptr::null_mut());
+            continue;
+        }
+        let llvm_fnc = if resolve::crate_of(fnc_name).is_some() {
+            // A Rust path, so it's mangled
+            unsafe { resolve::find_function(module, fnc_name) }?
+        } else {
+            let c_fnc_name = c_name(fnc_name)?;
+            unsafe { LLVMGetNamedFunction(module, c_fnc_name.as_ptr()) }
+        };
+        if llvm_fnc.is_null() {
+            return Err(EnzymeBuildError::MissingFunction {
+                name: fnc_name.clone(),
+                near_matches: vec![],
+            });
+        }
         functions.push(llvm_fnc);
     }
-    assert_eq!(
-        functions.len(),
-        fnc_names.len(),
-        "We couldn't find all functions, that's a bug. Please report it!"
-    );
-    functions
+    Ok(functions)
 }
 
 fn generate_grad_function(
     mut functions: Vec<LLVMValueRef>,
-    grad_names: Vec<String>,
-    mut param_infos: Vec<ParamInfos>,
-) -> Vec<LLVMValueRef> {
-    let opt_grads = !cfg!(debug_assertions); // There should be a better solution
-    let auto_diff = AutoDiff::new(opt_grads);
+    mut fnc_infos: Vec<FncInfo>,
+    context: LLVMContextRef,
+    tape_sizes: &mut Vec<(String, u64)>,
+    config: &Config,
+) -> Result<Vec<LLVMValueRef>, EnzymeBuildError> {
+    let auto_diff = AutoDiff::new(config.opt_level.optimize());
+
+    // Split mode generates two functions, all other modes just one.
+    let num_expected: usize = fnc_infos
+        .iter()
+        .map(|info| info.generated_names().len())
+        .sum();
+
+    // Generated functions by name, so we can differentiate them again.
+    let mut generated: HashMap<String, LLVMValueRef> = HashMap::new();
 
     let mut grad_fncs = vec![];
-    for (&mut fnc, (param_info, grad_name)) in functions
-        .iter_mut()
-        .zip(param_infos.iter_mut().zip(grad_names.iter()))
-    {
-        dbg!(grad_name);
-        let grad_func: LLVMValueRef = auto_diff.create_primal_and_gradient(
-            fnc as *mut LLVMOpaqueValue,
-            &mut param_info.input_activity,
-            param_info.ret_info,
-        ) as LLVMValueRef;
-        dbg!("Generated gradient function");
+    for (fnc, info) in functions.iter_mut().zip(fnc_infos.iter_mut()) {
+        let grad_name = &info.grad_name;
+        if fnc.is_null() {
+            // Higher-order derivative, our primary function is a previously generated one.
+            *fnc = *generated.get(&info.primary_name).ok_or_else(|| {
+                EnzymeBuildError::Internal(format!(
+                    "Higher-order derivatives must be ordered after their primal {}.",
+                    info.primary_name
+                ))
+            })?;
+            verify::verify_generated_primal(info, *fnc, context)?;
+        }
+        let fnc = *fnc;
+        let param_info = &mut info.params;
+        let first_new = grad_fncs.len();
+
+        // Tell Enzyme what we know about the types, instead of letting it guess everything.
+        let mut type_info = unsafe { FncTypeInfo::from_llvm_function(fnc) };
+        // Rust's debug info knows more than the LLVM types, e.g. which fields are floats.
+        if let Some(trees) = unsafe { dwarf::param_type_trees(fnc) } {
+            type_info.replace_args(trees);
+        }
+        let grad_func: LLVMValueRef = match param_info.mode {
+            DiffMode::Forward => {
+                auto_diff.create_fwd_diff(fnc as *mut LLVMOpaqueValue, param_info, &type_info)
+            }
+            DiffMode::Reverse => auto_diff.create_primal_and_gradient(
+                fnc as *mut LLVMOpaqueValue,
+                param_info,
+                &type_info,
+            ),
+            DiffMode::ReverseSplit => {
+                let split =
+                    auto_diff.create_split(fnc as *mut LLVMOpaqueValue, param_info, &type_info);
+                tape_sizes.push((
+                    grad_name.clone(),
+                    tape_size(fnc, split.tape_type as LLVMTypeRef),
+                ));
+                grad_fncs.push(split.augmented as LLVMValueRef);
+                split.gradient
+            }
+        } as LLVMValueRef;
+        if grad_func.is_null() {
+            return Err(EnzymeBuildError::Internal(format!(
+                "Enzyme failed to generate {}.",
+                grad_name
+            )));
+        }
         grad_fncs.push(grad_func);
-        let llvm_grad_fnc_type = unsafe { LLVMTypeOf(grad_func) };
-        dbg!(get_type(llvm_grad_fnc_type));
-        dbg!(unsafe { LLVMCountParams(grad_func) });
-        /*
-        #[allow(deprecated)]
-        let llvm_name = LLVMGetValueName(grad_func);
-        let name = CString::from_raw(llvm_name as *mut i8);
-        dbg!(name);*/
-        dbg!(grad_func);
-        dbg!();
+        for (name, &new_fnc) in info
+            .generated_names()
+            .into_iter()
+            .zip(&grad_fncs[first_new..])
+        {
+            generated.insert(name, new_fnc);
+        }
     }
-    assert_eq!(
-        grad_fncs.len(),
-        functions.len(),
-        "We failed generating all gradient functions. Please report it!"
-    );
-    grad_fncs
+    if grad_fncs.len() != num_expected {
+        return Err(EnzymeBuildError::Internal(
+            "We failed generating all gradient functions.".to_owned(),
+        ));
+    }
+    Ok(grad_fncs)
 }
 
-fn print_ffi_type(module: LLVMModuleRef, ffi_names: Vec<String>) {
+/// The size of the tape in bytes, so the crate knows how much memory it keeps around.
+/// A null tape type means that Enzyme didn't need to cache anything.
+fn tape_size(primary: LLVMValueRef, tape_type: LLVMTypeRef) -> u64 {
+    if tape_type.is_null() {
+        return 0;
+    }
     unsafe {
-        for name in ffi_names {
-            let c_fnc_name = CString::new(name.clone()).unwrap();
-            let u_fnc: LLVMValueRef = LLVMGetNamedFunction(module, c_fnc_name.as_ptr()); // get the U(ndefined) fnc symbol
-            assert_ne!(u_fnc as usize, 0, "couldn't get undef symbol {}", name);
-
-            let u_type: LLVMTypeRef = LLVMTypeOf(u_fnc);
-            let u_return_type = LLVMGetReturnType(LLVMGetElementType(u_type));
-
-            dbg!("Expected type for ", name);
-            dbg!(get_type(u_type));
-            dbg!(get_type(u_return_type));
-            dbg!();
-        }
+        let data_layout = LLVMGetModuleDataLayout(LLVMGetGlobalParent(primary));
+        LLVMABISizeOfType(data_layout, tape_type)
     }
 }
 
@@ -284,70 +472,97 @@ fn handle_ffi(
     context: LLVMContextRef,
     grad_functions: &mut [LLVMValueRef],
     grad_names: Vec<String>,
-) {
+    intermediate_names: &[String],
+) -> Result<(), EnzymeBuildError> {
     for i in 0..grad_functions.len() {
         let grad_name = &grad_names[i];
 
         // rename grad fnc to tmp name (to not hide equally named undef symbols anymore)
         let tmp = "tmp_diffe".to_owned() + grad_name;
-        let c_tmp = CString::new(tmp.clone()).unwrap();
+        let c_tmp = c_name(&tmp)?;
         unsafe {
             LLVMSetValueName2(grad_functions[i], c_tmp.as_ptr(), tmp.len() as usize);
         }
 
         // access undef symbols
-        let c_fnc_name = CString::new(grad_name.clone()).unwrap();
+        let c_fnc_name = c_name(grad_name)?;
         // get the U(ndefined) fnc symbol
         let u_fnc: LLVMValueRef = unsafe { LLVMGetNamedFunction(module, c_fnc_name.as_ptr()) };
-        assert_ne!(
-            u_fnc as usize, 0,
-            "Couldn't get undef symbol for {}. \
-                   Do you declare your gradient function in an extern block?",
-            grad_name
-        );
+        if u_fnc.is_null() && intermediate_names.contains(grad_name) {
+            // Only used to generate a higher-order derivative, so nobody calls it from Rust.
+            unsafe {
+                LLVMSetValueName2(grad_functions[i], c_fnc_name.as_ptr(), grad_name.len());
+            }
+            continue;
+        }
+        if u_fnc.is_null() {
+            return Err(EnzymeBuildError::MissingDeclaration(grad_name.clone()));
+        }
 
         unsafe {
             let u_type: LLVMTypeRef = LLVMTypeOf(u_fnc);
-            let f_type: LLVMTypeRef = LLVMTypeOf(grad_functions[i]);
+            let mut f_type: LLVMTypeRef = LLVMTypeOf(grad_functions[i]);
+
+            if u_type != f_type && wrappers::has_vector_args(grad_functions[i]) {
+                // In vector mode Enzyme passes all lanes of a shadow argument as one array,
+                // while the Rust declaration lists them as consecutive arguments.
+                grad_functions[i] = wrappers::expand_vector_args(
+                    module,
+                    context,
+                    grad_functions[i],
+                    "vec_".to_owned() + grad_name,
+                )?;
+                f_type = LLVMTypeOf(grad_functions[i]);
+            }
+
             let u_return_type = LLVMGetReturnType(LLVMGetElementType(u_type));
             let f_return_type = LLVMGetReturnType(LLVMGetElementType(f_type));
 
             if u_type != f_type {
-                dbg!("Some type missmatch happened for ".to_owned() + &grad_names[i]);
-                dbg!(get_type(u_type));
-                dbg!(get_type(f_type));
-                dbg!(get_type(u_return_type));
-                dbg!(get_type(f_return_type));
-                dbg!();
-
                 // TODO: Check for 2xf32 -> 1xf64 changes
                 let num_elem_in_ret_struct = LLVMCountStructElementTypes(f_return_type);
 
-                if num_elem_in_ret_struct > 2 {
-                    dbg!("move_return_into_args");
+                if u_return_type == LLVMVoidTypeInContext(context) && num_elem_in_ret_struct > 1 {
                     // The C-Abi will change a function returning a struct with more than
                     // two double values by returning void and moving the actual return struct
                     // into the parameter list, at the first position.
+                    // The same happens for smaller structs holding the lanes of vector mode.
                     grad_functions[i] = wrappers::move_return_into_args(
                         module,
                         context,
                         grad_functions[i],
                         u_type,
                         grad_name.clone(),
-                    );
+                    )?;
                 } else if num_elem_in_ret_struct == 1 {
-                    dbg!("extract_return_type");
                     // The C-Abi will change a function returning a struct { double } with exactly
                     // one double value to just return the double, stripping the struct.
+                    // The same happens for the single shadow pointer of DuplicatedNoNeed.
                     grad_functions[i] = wrappers::extract_return_type(
                         module,
                         context,
                         grad_functions[i],
                         u_type,
                         grad_name.clone(),
-                    );
+                    )?;
+                } else if LLVMGetTypeKind(u_return_type) == LLVMTypeKind::LLVMStructTypeKind
+                    && LLVMCountStructElementTypes(u_return_type) == num_elem_in_ret_struct
+                {
+                    // The C-Abi passes the primal and shadow pointer of a Duplicated return
+                    // as integers, while Enzyme returns them as pointers.
+                    grad_functions[i] = wrappers::cast_return_type(
+                        module,
+                        context,
+                        grad_functions[i],
+                        u_type,
+                        grad_name.clone(),
+                    )?;
                 } else {
-                    panic!("Unhandled type missmatch. Please report this.");
+                    return Err(EnzymeBuildError::TypeMismatch {
+                        function: grad_name.clone(),
+                        declared: get_type(u_type).to_string_lossy().into_owned(),
+                        generated: get_type(f_type).to_string_lossy().into_owned(),
+                    });
                 }
             }
 
@@ -361,53 +576,60 @@ fn handle_ffi(
             );
         }
     }
+    Ok(())
 }
 
-fn dumb_module_to_obj(module: LLVMModuleRef, context: LLVMContextRef, out_obj: &Path) {
+fn dumb_module_to_obj(
+    module: LLVMModuleRef,
+    context: LLVMContextRef,
+    out_obj: &Path,
+    config: &Config,
+) -> Result<(), EnzymeBuildError> {
+    let c_out_obj = c_path(out_obj)?;
     unsafe {
-        let target_machine = create_target_machine();
+        let target_machine = create_target_machine(config)?;
         let mut msg = ptr::null_mut();
-        let c_out_obj = CString::new(out_obj.to_str().unwrap().to_owned())
-            .unwrap()
-            .into_raw();
-        assert!(
-            LLVMTargetMachineEmitToFile(
-                target_machine,
-                module,
-                c_out_obj,
-                LLVMCodeGenFileType::LLVMObjectFile,
-                &mut msg
-            ) == 0,
-            "filename: {:?}, error: {:?}",
-            out_obj,
-            CStr::from_ptr(msg).to_str().unwrap()
-        );
+        let failed = LLVMTargetMachineEmitToFile(
+            target_machine,
+            module,
+            c_out_obj.as_ptr() as *mut _,
+            LLVMCodeGenFileType::LLVMObjectFile,
+            &mut msg,
+        ) != 0;
+        let res = if failed {
+            Err(EnzymeBuildError::Codegen(format!(
+                "filename: {:?}, error: {}",
+                out_obj,
+                CStr::from_ptr(msg).to_string_lossy()
+            )))
+        } else {
+            Ok(())
+        };
         LLVMDisposeMessage(msg);
         LLVMDisposeTargetMachine(target_machine);
         LLVMDisposeModule(module);
         LLVMContextDispose(context);
+        res
     }
 }
 
+/// Internalize everything but the gradients. Declarations keep their linkage, since internal
+/// declarations are invalid and would break the passes which we run afterwards.
 fn only_expose_gradients(module: LLVMModuleRef, fncs: Vec<LLVMValueRef>) {
     unsafe {
         // All functions
         let mut symbol = LLVMGetFirstFunction(module);
-        let last_symbol = LLVMGetLastFunction(module);
-        while symbol != last_symbol {
-            LLVMSetLinkage(symbol, LLVMLinkage::LLVMInternalLinkage);
+        while !symbol.is_null() {
+            internalize(symbol);
             symbol = LLVMGetNextFunction(symbol);
         }
-        LLVMSetLinkage(symbol, LLVMLinkage::LLVMInternalLinkage);
 
         // Global Symbols
         let mut symbol = LLVMGetFirstGlobal(module);
-        let last_symbol = LLVMGetLastGlobal(module);
-        while symbol != last_symbol {
-            LLVMSetLinkage(symbol, LLVMLinkage::LLVMInternalLinkage);
+        while !symbol.is_null() {
+            internalize(symbol);
             symbol = LLVMGetNextGlobal(symbol);
         }
-        LLVMSetLinkage(symbol, LLVMLinkage::LLVMInternalLinkage);
     }
 
     for grad_fnc in fncs {
@@ -417,23 +639,31 @@ fn only_expose_gradients(module: LLVMModuleRef, fncs: Vec<LLVMValueRef>) {
     }
 }
 
-fn list_functions(module: LLVMModuleRef) -> Vec<LLVMValueRef> {
+unsafe fn internalize(symbol: LLVMValueRef) {
+    if LLVMIsDeclaration(symbol) == 0 {
+        LLVMSetLinkage(symbol, LLVMLinkage::LLVMInternalLinkage);
+    }
+}
+
+fn list_functions(module: LLVMModuleRef) -> Result<Vec<LLVMValueRef>, EnzymeBuildError> {
     unsafe {
         let mut res = vec![];
         let mut symbol = LLVMGetFirstFunction(module);
         let last_symbol = LLVMGetLastFunction(module);
         if symbol == last_symbol {
-            panic!("Found no symbols in module");
+            return Err(EnzymeBuildError::Internal(
+                "Found no symbols in module.".to_owned(),
+            ));
         }
         while symbol != last_symbol {
             res.push(symbol);
             symbol = LLVMGetNextFunction(symbol);
         }
-        res
+        Ok(res)
     }
 }
 
-fn remove_functions(fncs: Vec<LLVMValueRef>) {
+fn remove_functions(fncs: Vec<LLVMValueRef>) -> Result<(), EnzymeBuildError> {
     unsafe {
         for fnc in fncs {
             let num = LLVMCountBasicBlocks(fnc);
@@ -443,8 +673,11 @@ fn remove_functions(fncs: Vec<LLVMValueRef>) {
                 LLVMDeleteBasicBlock(block);
                 // LLVMRemoveBasicBlockFromParent(block);
             }
-            if let Err(e) = verify::verify_function(fnc) {
-                panic!("Creating a wrapper function failed! {}", e);
+            if let Err(message) = verify::verify_function(fnc) {
+                return Err(EnzymeBuildError::InvalidModule {
+                    stage: "after removing the original functions".to_owned(),
+                    message,
+                });
             }
 
             // LLVMDeleteFunction(fnc); // Breaks other things
@@ -452,98 +685,287 @@ fn remove_functions(fncs: Vec<LLVMValueRef>) {
             // or probably better LLVMVerifyModule
         }
     }
+    Ok(())
+}
+
+/// The file name of the library (or object) which we build, platform dependent for cdylibs.
+fn artifact_name(config: &Config) -> Result<String, EnzymeBuildError> {
+    let name = &config.lib_name;
+    Ok(match config.output {
+        OutputKind::StaticLib => format!("lib{}.a", name),
+        OutputKind::Object => format!("{}.o", name),
+        OutputKind::CDylib => match env_var("CARGO_CFG_TARGET_OS")?.as_str() {
+            "macos" | "ios" => format!("lib{}.dylib", name),
+            "windows" => format!("{}.dll", name),
+            _ => format!("lib{}.so", name),
+        },
+    })
 }
 
-fn build_archive(primary_fnc_infos: Vec<FncInfo>) {
-    let entry_path = PathBuf::from(env::var("OUT_DIR").unwrap());
-    let out_obj = entry_path.with_file_name("result").with_extension("o");
-    let out_archive = entry_path
-        .join("libGradFunc.a")
-        .into_os_string()
-        .into_string()
-        .unwrap();
+/// Turn the object file into the requested kind of artifact.
+fn package_artifact(
+    out_obj: &Path,
+    artifact: &Path,
+    config: &Config,
+) -> Result<(), EnzymeBuildError> {
+    match config.output {
+        // That's already the object file.
+        OutputKind::Object => Ok(()),
+        OutputKind::StaticLib => cc::Build::new()
+            .object(out_obj)
+            .cargo_metadata(false)
+            .try_compile(&config.lib_name)
+            .map_err(|e| EnzymeBuildError::ToolFailed {
+                command: "ar".to_owned(),
+                status: None,
+                output: e.to_string(),
+            }),
+        OutputKind::CDylib => {
+            // cc knows the right compiler driver for the target, we let it do the linking.
+            let compiler = cc::Build::new()
+                .cargo_metadata(false)
+                .try_get_compiler()
+                .map_err(|e| EnzymeBuildError::ToolFailed {
+                    command: "cc".to_owned(),
+                    status: None,
+                    output: e.to_string(),
+                })?;
+            let mut link = compiler.to_command();
+            link.arg("-shared").arg("-o").arg(artifact).arg(out_obj);
+            run_and_printerror(&mut link)
+        }
+    }
+}
+
+/// Tell cargo how to link the artifact into the crate.
+fn print_link_directives(out_dir: &Path, artifact: &Path, config: &Config) {
+    match config.output {
+        OutputKind::StaticLib => {
+            println!("cargo:rustc-link-search=native={}", out_dir.display());
+            println!("cargo:rustc-link-lib=static={}", config.lib_name);
+        }
+        OutputKind::CDylib => {
+            println!("cargo:rustc-link-search=native={}", out_dir.display());
+            println!("cargo:rustc-link-lib=dylib={}", config.lib_name);
+        }
+        OutputKind::Object => {
+            println!("cargo:rustc-link-arg={}", artifact.display());
+        }
+    }
+}
+
+fn build_archive(
+    deps_dir: &Path,
+    primary_fnc_infos: Vec<FncInfo>,
+    config: &Config,
+    artifact: &Path,
+) -> Result<(), EnzymeBuildError> {
+    let entry_path = PathBuf::from(env_var("OUT_DIR")?);
+    let out_obj = match config.output {
+        OutputKind::Object => artifact.to_owned(),
+        _ => entry_path.join(config.lib_name.clone() + ".o"),
+    };
+
+    // `crate::` paths refer to the crate calling us
+    let crate_name = env_var("CARGO_PKG_NAME")?.replace('-', "_");
+    let primary_fnc_infos: Vec<FncInfo> = primary_fnc_infos
+        .into_iter()
+        .map(|mut info| {
+            info.primary_name = resolve::normalize_path(&info.primary_name, &crate_name);
+            info
+        })
+        .collect();
+
+    // Patterns select the functions of our crate and the crates which they name.
+    let primary_names: Vec<String> = primary_fnc_infos
+        .iter()
+        .map(|info| info.primary_name.clone())
+        .collect();
+    let (crate_bc_files, other_bc_files) = find_bc_files(deps_dir, &primary_names)?;
+    let primary_fnc_infos = pattern::expand_patterns(primary_fnc_infos, &crate_bc_files)?;
+
+    // Higher-order derivatives differentiate generated functions, so those have to come first.
+    let primary_fnc_infos = verify::order_by_dependency(primary_fnc_infos)?;
 
     // Let's split it up so we can just pass those values which ufnction need.
-    let (mut primary_names, mut grad_names, mut parameter_informations) = (vec![], vec![], vec![]);
+    let (mut primary_names, mut generated_names) = (vec![], vec![]);
     for info in primary_fnc_infos.clone() {
+        generated_names.append(&mut info.generated_names());
         primary_names.push(info.primary_name);
-        grad_names.push(info.grad_name);
-        parameter_informations.push(info.params);
     }
+    // Generated functions which are differentiated again, the user might not declare them.
+    let intermediate_names: Vec<String> = generated_names
+        .iter()
+        .filter(|name| primary_names.contains(name))
+        .cloned()
+        .collect();
+    let existing_names: Vec<String> = primary_names
+        .iter()
+        .filter(|name| !generated_names.contains(name))
+        .cloned()
+        .collect();
+
+    // Merge the bitcode files with some care to have all the code which we might differentiate
+    let merged_bc = link_bc_files(&crate_bc_files, &other_bc_files, existing_names)?;
+
+    let dumper = dump::IrDumper::new(config.dump_ir.as_deref())?;
+
+    // Nothing changed since the last run, so we can keep the old archive.
+    // The user wants to see the IR if dumping it, so we have to regenerate it then.
+    let cache = cache::ArchiveCache::new(&entry_path, &merged_bc, &primary_fnc_infos, config)?;
+    let gradients_rs = entry_path.join(bindings::GRADIENTS_FILE);
+    let cached = !dumper.is_enabled() && gradients_rs.exists() && cache.lookup(artifact);
+    if cached {
+        return Ok(());
+    }
+    cache.clear();
 
-    // Merge and load the bitcode files with some care to have all the code which we might differentiate
-    let (module, context) = read_bc_files(primary_names.clone());
+    let (module, context) = read_bc_file(&merged_bc)?;
+    dumper.dump(module, "01-merged")?;
+    passes::run_passes(module, &config.pre_ad_passes, config)?;
 
     // Store existing functions name to clean up later
-    let junk_fnc = list_functions(module);
-
-    // Just for debugging purpose, some type infos
-    print_ffi_type(module, grad_names.clone());
+    let junk_fnc = list_functions(module)?;
 
     // We are loading the existing primary functions, to pass them to enzyme.
-    let functions = load_primary_functions(module, primary_names.clone());
+    let functions = load_primary_functions(module, primary_names, &generated_names)?;
 
-    if let Err(e) = verify::verify_user_inputs(primary_fnc_infos, functions.clone(), context) {
-        panic!("The primary function which you wrote does not work with the FncInfo which you gave! {}", e);
+    verify::verify_user_inputs(primary_fnc_infos.clone(), functions.clone(), context)?;
+
+    // The primal of each generated function, to declare them with the right Rust types
+    let mut primals = vec![];
+    for (&fnc, info) in functions.iter().zip(&primary_fnc_infos) {
+        for _ in info.generated_names() {
+            primals.push((fnc, info.clone()));
+        }
     }
 
     // Now we generate the gradients based on our input and the selected activity values for
     // their parameters
-    enzyme_print_type(cfg!(debug_assertions)); // print generated functions in debug mode
-    let mut grad_fncs =
-        generate_grad_function(functions, grad_names.clone(), parameter_informations);
+    enzyme_print_type(config.debug); // print generated functions in debug mode
+    let mut tape_sizes = vec![];
+    let grad_fncs = generate_grad_function(
+        functions,
+        primary_fnc_infos,
+        context,
+        &mut tape_sizes,
+        config,
+    );
     enzyme_print_type(false);
+    let mut grad_fncs = grad_fncs?;
+    dumper.dump(module, "02-enzyme")?;
+
+    // Tell the crate how to call them
+    let declarations = bindings::declarations(
+        &grad_fncs,
+        &generated_names,
+        &primals,
+        &intermediate_names,
+        &tape_sizes,
+    );
+    bindings::write_if_changed(&gradients_rs, &declarations)?;
 
     // Now that we have the gradients, lets clean up
-    remove_functions(junk_fnc);
+    remove_functions(junk_fnc)?;
 
     // First, some magic to handle ffi
-    handle_ffi(module, context, &mut grad_fncs, grad_names.clone());
-
-    // The next step breaks some module rules, but is necessary to not multiple symbol definitions.
-    // So we check our module for other issues before.
-    if let Err(e) = unsafe { verify::verify_module(module) } {
-        panic!(
-            "Apparently we broke the module while working on it. Please report this! {}",
-            e
-        );
+    handle_ffi(
+        module,
+        context,
+        &mut grad_fncs,
+        generated_names,
+        &intermediate_names,
+    )?;
+    dumper.dump(module, "03-ffi")?;
+
+    // The next step hides all symbols but the gradients, to not have multiple symbol definitions.
+    // We check our module before, while the issues still show up under their original linkage.
+    if let Err(message) = unsafe { verify::verify_module(module) } {
+        return Err(EnzymeBuildError::InvalidModule {
+            stage: "after generating the gradients".to_owned(),
+            message,
+        });
     }
 
     // Next, we localize all other symbols, since we only want to expose the newly generated functions
     only_expose_gradients(module, grad_fncs);
+    // Also hide runtime symbols which std already defines in the crate linking us
+    localize::localize_symbols(module, &config.local_symbols);
+
+    // Only the gradients are left exposed, so the optimizer can drop everything else
+    passes::run_passes(module, &config.post_ad_passes, config)?;
 
     // And now we store all gradients in a single object file
-    dumb_module_to_obj(module, context, &out_obj);
-
-    // compile object file to static archive
-    cc::Build::new().object(out_obj).compile("GradFunc");
-
-    // And remove the extra __rust_probestack
-    // https://github.com/rust-lang/rust/issues/88274
-    let mut objcopy = Command::new(llvm_objcopy());
-    objcopy.args(&[
-        "--localize-symbol",
-        "__rust_probestack",
-        &out_archive,
-        &out_archive,
-    ]);
-    run_and_printerror(&mut objcopy);
+    dumper.dump(module, "04-codegen")?;
+    dumb_module_to_obj(module, context, &out_obj, config)?;
+
+    // and package it as the user asked for
+    package_artifact(&out_obj, artifact, config)?;
+
+    cache.store();
+    Ok(())
 }
 
+/// Generate the functions described by `primary_functions` and link them into the crate.
+///
+/// Call this from the build.rs of your crate. We compile the crate to LLVM bitcode through
+/// a nested cargo call, so a single `cargo build` is enough. Every call regenerates all
+/// functions, so it's safe to rerun.
 ///
+/// Panics with a description of the problem if anything fails, see `try_build`.
 pub fn build(primary_functions: Vec<FncInfo>) {
-    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
-    let control_file = out_path.join("enzyme-done");
-
-    if Path::exists(&control_file) {
-        dbg!("second call"); // now we create and link the archive from the .bc file
-        dbg!();
-        fs::remove_file(&control_file).unwrap();
-        build_archive(primary_functions);
-        println!("cargo:rustc-link-search={}", out_path.display()); // cc does that already afaik
-        println!("cargo:rustc-link-lib=static=GradFunc"); // cc does that already afaik
-    } else {
-        dbg!("first call"); // now cargo/rustc will generate the .bc file
-        fs::File::create(control_file).unwrap();
+    build_with(primary_functions, EnzymeConfig::new());
+}
+
+/// Same as `build`, but returns an error instead of panicking.
+pub fn try_build(primary_functions: Vec<FncInfo>) -> Result<(), EnzymeBuildError> {
+    try_build_with(primary_functions, EnzymeConfig::new())
+}
+
+/// Same as `build`, but uses the given settings instead of deriving all of them from cargo.
+pub fn build_with(primary_functions: Vec<FncInfo>, config: EnzymeConfig) {
+    if let Err(e) = try_build_with(primary_functions, config) {
+        panic!("{}", e);
+    }
+}
+
+/// Compile the bitcode once more, since it was compiled with other declarations.
+fn rebuild_archive(
+    primary_functions: Vec<FncInfo>,
+    config: &Config,
+    artifact: &Path,
+) -> Result<(), EnzymeBuildError> {
+    let deps_dir = compile_bitcode()?;
+    build_archive(&deps_dir, primary_functions, config, artifact)
+}
+
+/// Same as `build_with`, but returns an error instead of panicking.
+pub fn try_build_with(
+    primary_functions: Vec<FncInfo>,
+    config: EnzymeConfig,
+) -> Result<(), EnzymeBuildError> {
+    if env::var(BITCODE_PASS_ENV).is_ok() {
+        // We are the build script of the nested call, which only has to emit the bitcode.
+        return bindings::prepare_bitcode_pass();
+    }
+    let out_path = PathBuf::from(env_var("OUT_DIR")?);
+    let config = config.resolve()?;
+    let artifact = out_path.join(artifact_name(&config)?);
+    let gradients_rs = out_path.join(bindings::GRADIENTS_FILE);
+
+    let old_declarations = fs::read_to_string(&gradients_rs).ok();
+    let deps_dir = compile_bitcode()?;
+    let res = build_archive(&deps_dir, primary_functions.clone(), &config, &artifact);
+    let changed = fs::read_to_string(&gradients_rs).ok() != old_declarations;
+    match res {
+        // The bitcode was compiled with other declarations, so handle_ffi couldn't match them
+        // or built its wrappers for the old ones.
+        Ok(()) if changed => rebuild_archive(primary_functions, &config, &artifact)?,
+        Err(e) if changed && e.is_declaration_mismatch() => {
+            rebuild_archive(primary_functions, &config, &artifact)?
+        }
+        res => res?,
     }
+    print_link_directives(&out_path, &artifact, &config);
+    Ok(())
 }
@@ -0,0 +1,255 @@
+use crate::{c_name, c_path, resolve, EnzymeBuildError};
+use llvm_sys::bit_reader::LLVMParseBitcodeInContext2;
+use llvm_sys::bit_writer::LLVMWriteBitcodeToFile;
+use llvm_sys::core::*;
+use llvm_sys::linker::LLVMLinkModules2;
+use llvm_sys::prelude::*;
+use llvm_sys::LLVMLinkage;
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+/// Link the functions which we differentiate, and everything they need, into a single bc file.
+///
+/// We only keep the `roots` (the functions to differentiate) from `crate_files`, everything else
+/// of them and of `other_files` is only linked if some already linked code references it.
+/// Plain names are looked up as symbols, Rust paths through their demangled names.
+pub fn link_bitcode(
+    crate_files: &[PathBuf],
+    other_files: &[PathBuf],
+    roots: &[String],
+    out: &Path,
+) -> Result<(), EnzymeBuildError> {
+    unsafe {
+        let context = LLVMContextCreate();
+        let res = link_in_context(context, crate_files, other_files, roots, out);
+        LLVMContextDispose(context);
+        res
+    }
+}
+
+unsafe fn link_in_context(
+    context: LLVMContextRef,
+    crate_files: &[PathBuf],
+    other_files: &[PathBuf],
+    roots: &[String],
+    out: &Path,
+) -> Result<(), EnzymeBuildError> {
+    let crate_modules = parse_all(context, crate_files)?;
+    let other_modules = parse_all(context, other_files)?;
+
+    // The merged module starts empty, but has to target the same machine.
+    let (_, first) = crate_modules[0];
+    let c_name = CString::new("merged").unwrap();
+    let merged = LLVMModuleCreateWithNameInContext(c_name.as_ptr(), context);
+    LLVMSetDataLayout(merged, LLVMGetDataLayoutStr(first));
+    LLVMSetTarget(merged, LLVMGetTarget(first));
+
+    let mut root_symbols = HashSet::new();
+    for &(_, module) in &crate_modules {
+        root_symbols.extend(find_roots(module, roots)?);
+    }
+    let modules: Vec<(PathBuf, LLVMModuleRef)> =
+        crate_modules.into_iter().chain(other_modules).collect();
+    for &(_, module) in &modules {
+        link_only_needed(module, &root_symbols);
+    }
+
+    let res = link_needed(merged, &modules, crate_files.len());
+    for (_, module) in modules {
+        LLVMDisposeModule(module);
+    }
+    if let Err(e) = res {
+        LLVMDisposeModule(merged);
+        return Err(e);
+    }
+
+    let c_out = c_path(out)?;
+    let failed = LLVMWriteBitcodeToFile(merged, c_out.as_ptr()) != 0;
+    LLVMDisposeModule(merged);
+    if failed {
+        return Err(EnzymeBuildError::Io {
+            path: out.to_owned(),
+            message: "Could not write the bitcode.".to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// LLVMLinkModules2 consumes its source, but we might need a module more than once,
+/// so we link clones.
+unsafe fn link_needed(
+    merged: LLVMModuleRef,
+    modules: &[(PathBuf, LLVMModuleRef)],
+    num_crate_modules: usize,
+) -> Result<(), EnzymeBuildError> {
+    // The roots are still external, so linking pulls them in with all they need from their module.
+    for (path, module) in &modules[..num_crate_modules] {
+        link_into(merged, LLVMCloneModule(*module), path)?;
+    }
+    // The roots are linked now. Later clones of the crate modules would define them again,
+    // so from now on they are only linked where needed, like everything else.
+    let no_roots = HashSet::new();
+    for &(_, module) in &modules[..num_crate_modules] {
+        link_only_needed(module, &no_roots);
+    }
+
+    // A module which we link might need something of a module we skipped before,
+    // so repeat until nothing is missing anymore.
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (path, module) in modules {
+            if defines_missing(merged, *module)? {
+                link_into(merged, LLVMCloneModule(*module), path)?;
+                changed = true;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse all bc files into modules of `context`.
+///
+/// # Safety
+///
+/// `context` must be a valid context. The caller has to dispose the modules.
+pub unsafe fn parse_all(
+    context: LLVMContextRef,
+    files: &[PathBuf],
+) -> Result<Vec<(PathBuf, LLVMModuleRef)>, EnzymeBuildError> {
+    files
+        .iter()
+        .map(|path| Ok((path.clone(), parse_bitcode(context, path)?)))
+        .collect()
+}
+
+unsafe fn parse_bitcode(
+    context: LLVMContextRef,
+    path: &Path,
+) -> Result<LLVMModuleRef, EnzymeBuildError> {
+    let c_file = c_path(path)?;
+    let mut memory_buf = ptr::null_mut();
+    let mut msg = ptr::null_mut();
+    if LLVMCreateMemoryBufferWithContentsOfFile(c_file.as_ptr(), &mut memory_buf, &mut msg) != 0 {
+        let message = CStr::from_ptr(msg).to_string_lossy().into_owned();
+        LLVMDisposeMessage(msg);
+        return Err(EnzymeBuildError::InvalidBitcode {
+            path: path.to_owned(),
+            message,
+        });
+    }
+    let mut module = ptr::null_mut();
+    let failed = LLVMParseBitcodeInContext2(context, memory_buf, &mut module) != 0;
+    LLVMDisposeMemoryBuffer(memory_buf);
+    if failed {
+        return Err(EnzymeBuildError::InvalidBitcode {
+            path: path.to_owned(),
+            message: "Could not parse the bitcode.".to_owned(),
+        });
+    }
+    Ok(module)
+}
+
+unsafe fn link_into(
+    merged: LLVMModuleRef,
+    module: LLVMModuleRef,
+    path: &Path,
+) -> Result<(), EnzymeBuildError> {
+    // LLVM reports the details through the diagnostic handler.
+    if LLVMLinkModules2(merged, module) != 0 {
+        return Err(EnzymeBuildError::InvalidBitcode {
+            path: path.to_owned(),
+            message: "Linking it failed.".to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// The symbol names of the roots which are defined in this module.
+/// Ambiguous paths keep all their matches, we report them once everything is linked.
+unsafe fn find_roots(
+    module: LLVMModuleRef,
+    roots: &[String],
+) -> Result<Vec<String>, EnzymeBuildError> {
+    let mut symbols = vec![];
+    for root in roots {
+        let fncs = if resolve::crate_of(root).is_some() {
+            resolve::matching_functions(module, root)
+        } else {
+            let c_root = c_name(root)?;
+            vec![LLVMGetNamedFunction(module, c_root.as_ptr())]
+        };
+        for fnc in fncs {
+            if !fnc.is_null() && LLVMIsDeclaration(fnc) == 0 {
+                symbols.push(resolve::symbol_name(fnc));
+            }
+        }
+    }
+    Ok(symbols)
+}
+
+/// The linker only copies linkonce definitions if they are referenced, which is exactly
+/// what we want for everything except our roots.
+unsafe fn link_only_needed(module: LLVMModuleRef, roots: &HashSet<String>) {
+    let mut fnc = LLVMGetFirstFunction(module);
+    while !fnc.is_null() {
+        make_linkonce(fnc, roots);
+        fnc = LLVMGetNextFunction(fnc);
+    }
+    let mut global = LLVMGetFirstGlobal(module);
+    while !global.is_null() {
+        make_linkonce(global, roots);
+        global = LLVMGetNextGlobal(global);
+    }
+}
+
+unsafe fn make_linkonce(value: LLVMValueRef, roots: &HashSet<String>) {
+    if LLVMIsDeclaration(value) != 0 {
+        return;
+    }
+    let linkage = LLVMGetLinkage(value);
+    if roots.contains(&resolve::symbol_name(value)) {
+        // Private functions and instances of generics are usually internal,
+        // but the linker would drop them unless they are used.
+        if matches!(
+            linkage,
+            LLVMLinkage::LLVMInternalLinkage | LLVMLinkage::LLVMPrivateLinkage
+        ) {
+            LLVMSetLinkage(value, LLVMLinkage::LLVMExternalLinkage);
+        }
+    } else if linkage == LLVMLinkage::LLVMExternalLinkage {
+        LLVMSetLinkage(value, LLVMLinkage::LLVMLinkOnceODRLinkage);
+    }
+}
+
+/// Does `module` define something which `merged` uses, but doesn't have yet?
+unsafe fn defines_missing(
+    merged: LLVMModuleRef,
+    module: LLVMModuleRef,
+) -> Result<bool, EnzymeBuildError> {
+    let is_defined = |value: LLVMValueRef| !value.is_null() && LLVMIsDeclaration(value) == 0;
+
+    let mut fnc = LLVMGetFirstFunction(merged);
+    while !fnc.is_null() {
+        if LLVMIsDeclaration(fnc) != 0 && LLVMGetIntrinsicID(fnc) == 0 {
+            let name = c_name(&resolve::symbol_name(fnc))?;
+            if is_defined(LLVMGetNamedFunction(module, name.as_ptr())) {
+                return Ok(true);
+            }
+        }
+        fnc = LLVMGetNextFunction(fnc);
+    }
+    let mut global = LLVMGetFirstGlobal(merged);
+    while !global.is_null() {
+        if LLVMIsDeclaration(global) != 0 {
+            let name = c_name(&resolve::symbol_name(global))?;
+            if is_defined(LLVMGetNamedGlobal(module, name.as_ptr())) {
+                return Ok(true);
+            }
+        }
+        global = LLVMGetNextGlobal(global);
+    }
+    Ok(false)
+}
@@ -0,0 +1,74 @@
+use crate::resolve;
+use llvm_sys::core::*;
+use llvm_sys::prelude::*;
+use llvm_sys::LLVMLinkage;
+use std::os::raw::c_char;
+use std::slice;
+
+/// Runtime symbols which end up in our object file, but are also defined by std in the crate
+/// linking it. Keeping them global breaks the link with duplicate symbols, see
+/// https://github.com/rust-lang/rust/issues/88274
+pub const DEFAULT_LOCAL_SYMBOLS: &[&str] = &[
+    "__rust_probestack",
+    "rust_begin_unwind",
+    "rust_eh_personality",
+    "rust_panic",
+    "__rust_alloc",
+    "__rust_dealloc",
+    "__rust_realloc",
+    "__rust_alloc_zeroed",
+    "__rust_alloc_error_handler",
+    "__rg_oom",
+];
+
+/// Hide the given symbols from the linker, whether they are LLVM functions and globals,
+/// or defined by module level assembly like `__rust_probestack`.
+pub fn localize_symbols(module: LLVMModuleRef, symbols: &[String]) {
+    unsafe {
+        let mut fnc = LLVMGetFirstFunction(module);
+        while !fnc.is_null() {
+            localize_value(fnc, symbols);
+            fnc = LLVMGetNextFunction(fnc);
+        }
+        let mut global = LLVMGetFirstGlobal(module);
+        while !global.is_null() {
+            localize_value(global, symbols);
+            global = LLVMGetNextGlobal(global);
+        }
+
+        let mut len = 0;
+        let asm = LLVMGetModuleInlineAsm(module, &mut len);
+        let asm = String::from_utf8_lossy(slice::from_raw_parts(asm as *const u8, len));
+        let localized = localize_asm(&asm, symbols);
+        if localized != asm {
+            LLVMSetModuleInlineAsm2(module, localized.as_ptr() as *const c_char, localized.len());
+        }
+    }
+}
+
+unsafe fn localize_value(value: LLVMValueRef, symbols: &[String]) {
+    if LLVMIsDeclaration(value) == 0 && symbols.contains(&resolve::symbol_name(value)) {
+        LLVMSetLinkage(value, LLVMLinkage::LLVMInternalLinkage);
+    }
+}
+
+/// Symbols defined in assembly are local unless declared otherwise, so we drop their `.globl`.
+fn localize_asm(asm: &str, symbols: &[String]) -> String {
+    let is_local = |name: &str| {
+        // Mach-O prefixes all symbols with an underscore.
+        let name = name.trim();
+        symbols
+            .iter()
+            .any(|symbol| name == symbol || name.strip_prefix('_') == Some(symbol))
+    };
+    asm.lines()
+        .filter(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some(".globl") | Some(".global"), Some(name)) => !is_local(name),
+                _ => true,
+            }
+        })
+        .map(|line| line.to_owned() + "\n")
+        .collect()
+}
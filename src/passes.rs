@@ -0,0 +1,69 @@
+use crate::config::{Config, OptLevel};
+use crate::{create_target_machine, EnzymeBuildError};
+use llvm_sys::error::{LLVMDisposeErrorMessage, LLVMGetErrorMessage};
+use llvm_sys::prelude::*;
+use llvm_sys::target_machine::LLVMDisposeTargetMachine;
+use llvm_sys::transforms::pass_builder::*;
+use std::ffi::{CStr, CString};
+
+/// LLVM passes which we run on the module, before or after Enzyme differentiated it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PassPipeline {
+    /// Don't run any passes.
+    None,
+    /// LLVM's default pipeline for the given opt level, like `opt -passes='default<O3>'`.
+    Preset(OptLevel),
+    /// Any pipeline in the syntax of `opt -passes=..`, e.g. `"mem2reg,sroa,loop-simplify"`.
+    Custom(String),
+}
+
+impl PassPipeline {
+    fn pipeline(&self) -> Option<String> {
+        match self {
+            PassPipeline::None => None,
+            PassPipeline::Preset(level) => {
+                let level = match level {
+                    OptLevel::O0 => "O0",
+                    OptLevel::O1 => "O1",
+                    OptLevel::O2 => "O2",
+                    OptLevel::O3 => "O3",
+                    OptLevel::Os => "Os",
+                    OptLevel::Oz => "Oz",
+                };
+                Some(format!("default<{}>", level))
+            }
+            PassPipeline::Custom(passes) if passes.is_empty() => None,
+            PassPipeline::Custom(passes) => Some(passes.clone()),
+        }
+    }
+}
+
+pub fn run_passes(
+    module: LLVMModuleRef,
+    passes: &PassPipeline,
+    config: &Config,
+) -> Result<(), EnzymeBuildError> {
+    let pipeline = match passes.pipeline() {
+        Some(pipeline) => pipeline,
+        None => return Ok(()),
+    };
+    let c_pipeline =
+        CString::new(pipeline.clone()).map_err(|_| EnzymeBuildError::PassesFailed {
+            pipeline: pipeline.clone(),
+            message: "The pipeline must not contain NUL bytes.".to_owned(),
+        })?;
+    let target_machine = create_target_machine(config)?;
+    unsafe {
+        let options = LLVMCreatePassBuilderOptions();
+        let error = LLVMRunPasses(module, c_pipeline.as_ptr(), target_machine, options);
+        LLVMDisposePassBuilderOptions(options);
+        LLVMDisposeTargetMachine(target_machine);
+        if !error.is_null() {
+            let msg = LLVMGetErrorMessage(error);
+            let message = CStr::from_ptr(msg).to_string_lossy().into_owned();
+            LLVMDisposeErrorMessage(msg);
+            return Err(EnzymeBuildError::PassesFailed { pipeline, message });
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,234 @@
+use crate::enzyme::{DiffMode, FncInfo, ReturnActivity, CDIFFE_TYPE};
+use crate::{bindings, dwarf, get_type, link, resolve, EnzymeBuildError};
+use glob::Pattern;
+use llvm_sys::core::*;
+use llvm_sys::prelude::*;
+use llvm_sys::LLVMTypeKind;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Replace every pattern FncInfo by one FncInfo per function in `crate_files` matching it.
+/// The other FncInfos are kept as they are.
+pub fn expand_patterns(
+    infos: Vec<FncInfo>,
+    crate_files: &[PathBuf],
+) -> Result<Vec<FncInfo>, EnzymeBuildError> {
+    if !infos.iter().any(|info| info.pattern) {
+        return Ok(infos);
+    }
+    unsafe {
+        let context = LLVMContextCreate();
+        let res = expand_in_context(context, infos, crate_files);
+        LLVMContextDispose(context);
+        res
+    }
+}
+
+unsafe fn expand_in_context(
+    context: LLVMContextRef,
+    infos: Vec<FncInfo>,
+    crate_files: &[PathBuf],
+) -> Result<Vec<FncInfo>, EnzymeBuildError> {
+    let modules = link::parse_all(context, crate_files)?;
+    let res = expand_all(&modules, infos);
+    for (_, module) in modules {
+        LLVMDisposeModule(module);
+    }
+    res
+}
+
+/// A function matched by a pattern, with the activities which we inferred for it.
+struct Match {
+    symbol: String,
+    path: String,
+    input_activity: Vec<CDIFFE_TYPE>,
+    ret_info: ReturnActivity,
+}
+
+unsafe fn expand_all(
+    modules: &[(PathBuf, LLVMModuleRef)],
+    infos: Vec<FncInfo>,
+) -> Result<Vec<FncInfo>, EnzymeBuildError> {
+    let mut matches: Vec<Vec<Match>> = vec![];
+    for info in &infos {
+        matches.push(if info.pattern {
+            find_matches(modules, info)?
+        } else {
+            vec![]
+        });
+    }
+
+    // Gradients are named after the last segment of the path, unless that is ambiguous,
+    // e.g. for `a::loss` and `b::loss`. Then we name them after the full path instead.
+    let mut taken: HashMap<String, usize> = HashMap::new();
+    for m in matches.iter().flatten() {
+        *taken.entry(identifier(last_segment(&m.path))).or_default() += 1;
+    }
+    for info in infos.iter().filter(|info| !info.pattern) {
+        for name in info.generated_names() {
+            for prefix in ["d_", "aug_"] {
+                if let Some(name) = name.strip_prefix(prefix) {
+                    *taken.entry(name.to_owned()).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    let mut expanded = vec![];
+    for (info, matches) in infos.into_iter().zip(matches) {
+        if !info.pattern {
+            expanded.push(info);
+            continue;
+        }
+        for m in matches {
+            let short_name = identifier(last_segment(&m.path));
+            let name = if taken[&short_name] > 1 {
+                identifier(&m.path)
+            } else {
+                short_name
+            };
+            expanded.push(info.matched(&m.symbol, &name, m.input_activity, m.ret_info));
+        }
+    }
+    Ok(expanded)
+}
+
+/// All functions matching the pattern of `info` whose activities we can infer.
+/// We skip the others with a warning, since a pattern can select many functions.
+unsafe fn find_matches(
+    modules: &[(PathBuf, LLVMModuleRef)],
+    info: &FncInfo,
+) -> Result<Vec<Match>, EnzymeBuildError> {
+    let invalid = |message: String| EnzymeBuildError::InvalidFncInfo {
+        function: info.primary_name.clone(),
+        message,
+    };
+    let pattern = Pattern::new(&info.primary_name)
+        .map_err(|e| invalid(format!("The pattern is not a valid glob: {}", e)))?;
+
+    let mut candidates = vec![];
+    for &(_, module) in modules {
+        candidates.extend(resolve::functions_matching(module, &pattern));
+    }
+    if candidates.is_empty() {
+        return Err(invalid(
+            "The pattern doesn't match any function of the bitcode.".to_owned(),
+        ));
+    }
+
+    let mut matches = vec![];
+    for (fnc, path) in candidates {
+        match infer_activities(fnc, info.params.mode) {
+            Ok((input_activity, ret_info)) => matches.push(Match {
+                // The symbol is unique, even if several instances of a generic share their path.
+                symbol: resolve::symbol_name(fnc),
+                path,
+                input_activity,
+                ret_info,
+            }),
+            Err(e) => println!(
+                "cargo:warning=Skipping {} of the pattern {}, since {}",
+                path, info.primary_name, e
+            ),
+        }
+    }
+    if matches.is_empty() {
+        return Err(invalid(
+            "We can't infer the activities of any function matching the pattern.".to_owned(),
+        ));
+    }
+    Ok(matches)
+}
+
+/// Guess the activities of a function from its LLVM signature.
+///
+/// An integer argument might be a float aggregate in disguise, so it is only Constant
+/// if the debug info tells us that it really is an integer.
+unsafe fn infer_activities(
+    fnc: LLVMValueRef,
+    mode: DiffMode,
+) -> Result<(Vec<CDIFFE_TYPE>, ReturnActivity), String> {
+    if dwarf::has_sret(fnc) {
+        return Err("it returns its value through a pointer argument.".to_owned());
+    }
+    let rust_types = dwarf::param_type_names(fnc).unwrap_or_default();
+    let is_integer = |i: usize| {
+        rust_types.get(i).cloned().flatten().map_or(false, |name| {
+            bindings::INTEGER_TYPES.contains(&name.as_str())
+        })
+    };
+
+    let fnc_type = LLVMGlobalGetValueType(fnc);
+    let mut input_activity = vec![];
+    for i in 0..LLVMCountParams(fnc) {
+        let ty = LLVMTypeOf(LLVMGetParam(fnc, i));
+        let activity = match LLVMGetTypeKind(ty) {
+            kind if is_float(kind) && mode == DiffMode::Forward => CDIFFE_TYPE::DFT_DUP_ARG,
+            kind if is_float(kind) => CDIFFE_TYPE::DFT_OUT_DIFF,
+            LLVMTypeKind::LLVMPointerTypeKind => CDIFFE_TYPE::DFT_DUP_ARG,
+            LLVMTypeKind::LLVMIntegerTypeKind if is_integer(i as usize) => {
+                CDIFFE_TYPE::DFT_CONSTANT
+            }
+            _ => {
+                return Err(format!(
+                    "we can't infer the activity of its argument {} of type {:?}.",
+                    i,
+                    get_type(ty)
+                ))
+            }
+        };
+        input_activity.push(activity);
+    }
+
+    let ret = LLVMGetReturnType(fnc_type);
+    let ret_info = match LLVMGetTypeKind(ret) {
+        LLVMTypeKind::LLVMVoidTypeKind => ReturnActivity::None,
+        kind if is_float(kind) => ReturnActivity::Active,
+        // The combined reverse mode can't return a shadow which the caller could still seed.
+        LLVMTypeKind::LLVMPointerTypeKind if mode == DiffMode::Reverse => ReturnActivity::Constant,
+        LLVMTypeKind::LLVMPointerTypeKind => ReturnActivity::Duplicated,
+        _ => {
+            return Err(format!(
+                "we can't infer the activity of its return type {:?}.",
+                get_type(ret)
+            ))
+        }
+    };
+    Ok((input_activity, ret_info))
+}
+
+fn is_float(kind: LLVMTypeKind) -> bool {
+    matches!(
+        kind,
+        LLVMTypeKind::LLVMFloatTypeKind | LLVMTypeKind::LLVMDoubleTypeKind
+    )
+}
+
+/// The last segment of a path, ignoring the `::` inside of generic arguments.
+fn last_segment(path: &str) -> &str {
+    let mut depth = 0;
+    let mut start = 0;
+    let bytes = path.as_bytes();
+    for (i, &c) in bytes.iter().enumerate() {
+        match c {
+            b'<' => depth += 1,
+            b'>' => depth -= 1,
+            b':' if depth == 0 && i > 0 && bytes[i - 1] == b':' => start = i + 1,
+            _ => {}
+        }
+    }
+    &path[start..]
+}
+
+/// Turn a path segment like `loss<f32>` into a valid Rust identifier like `loss_f32`.
+fn identifier(segment: &str) -> String {
+    let replaced: String = segment
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    replaced
+        .split('_')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>()
+        .join("_")
+}
@@ -0,0 +1,204 @@
+use crate::EnzymeBuildError;
+use glob::Pattern;
+use llvm_sys::core::*;
+use llvm_sys::prelude::*;
+use rustc_demangle::try_demangle;
+use std::slice;
+
+// How many near matches we list if we can't find a function.
+const MAX_NEAR_MATCHES: usize = 5;
+
+/// The crate of a Rust path like `simple_dep::f` or `<simple_dep::Foo>::f`.
+/// None for plain (unmangled) symbol names.
+pub fn crate_of(path: &str) -> Option<&str> {
+    path.trim_start_matches('<')
+        .split_once("::")
+        .map(|(krate, _)| krate)
+}
+
+/// Bring a Rust path into the form which we compare against the demangled names.
+///
+/// A leading `crate::` is replaced by the name of our crate, like Rust does. Paths starting
+/// with a generic like `loss::<f32>` or `Model<3>::energy` are relative to our crate as well.
+pub fn normalize_path(path: &str, crate_name: &str) -> String {
+    let path = canonical_path(path);
+    if let Some(rest) = path.strip_prefix("crate::") {
+        return format!("{}::{}", crate_name, rest);
+    }
+    let first_segment = path.split("::").next().unwrap_or("");
+    if first_segment.contains('<') {
+        return format!("{}::{}", crate_name, path);
+    }
+    path
+}
+
+/// Generic paths can be written in several ways, so we remove the differences:
+/// `my_crate::loss::<f32, 3>` becomes `my_crate::loss<f32,3>`, and `<my_crate::Model<3>>::energy`
+/// becomes `my_crate::Model<3>::energy`. Trait impls like `<A as B>::f` keep their brackets.
+pub fn canonical_path(path: &str) -> String {
+    let path = path
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .replace(", ", ",")
+        .replace("::<", "<");
+    if !path.starts_with('<') {
+        return path;
+    }
+    // Find the '>' closing the leading '<'
+    let mut depth = 0;
+    for (i, c) in path.char_indices() {
+        match c {
+            '<' => depth += 1,
+            '>' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            let self_type = &path[1..i];
+            if self_type.contains(" as ") {
+                return path;
+            }
+            return self_type.to_owned() + &path[i + 1..];
+        }
+    }
+    path
+}
+
+/// The name of the function as LLVM knows it.
+///
+/// # Safety
+///
+/// `fnc` must be a valid function.
+pub unsafe fn symbol_name(fnc: LLVMValueRef) -> String {
+    let mut len = 0;
+    let name = LLVMGetValueName2(fnc, &mut len);
+    let name = slice::from_raw_parts(name as *const u8, len);
+    String::from_utf8_lossy(name).into_owned()
+}
+
+/// The Rust path of a mangled symbol (legacy or v0 scheme), without the trailing hash.
+/// Only v0 keeps the generic arguments of the instance. The path is in canonical form.
+pub fn demangled_path(symbol: &str) -> Option<String> {
+    try_demangle(symbol)
+        .ok()
+        .map(|name| canonical_path(&format!("{:#}", name)))
+}
+
+/// All function definitions of the module, together with their symbol and demangled name.
+unsafe fn definitions(module: LLVMModuleRef) -> Vec<(LLVMValueRef, String, Option<String>)> {
+    let mut res = vec![];
+    let mut fnc = LLVMGetFirstFunction(module);
+    while !fnc.is_null() {
+        if LLVMIsDeclaration(fnc) == 0 {
+            let symbol = symbol_name(fnc);
+            let path = demangled_path(&symbol);
+            res.push((fnc, symbol, path));
+        }
+        fnc = LLVMGetNextFunction(fnc);
+    }
+    res
+}
+
+/// All definitions of a Rust path like `simple_dep::f` (or of a mangled symbol) in the module.
+/// Such functions don't keep their name in LLVM, so we compare their demangled names.
+/// Generic functions can have more than one.
+///
+/// # Safety
+///
+/// `module` must be a valid module.
+pub unsafe fn matching_functions(module: LLVMModuleRef, path: &str) -> Vec<LLVMValueRef> {
+    definitions(module)
+        .into_iter()
+        .filter(|(_, symbol, demangled)| symbol == path || demangled.as_deref() == Some(path))
+        .map(|(fnc, _, _)| fnc)
+        .collect()
+}
+
+/// All definitions whose name matches the glob, together with that name.
+/// The name is the demangled path for Rust functions and the symbol otherwise.
+///
+/// # Safety
+///
+/// `module` must be a valid module.
+pub unsafe fn functions_matching(
+    module: LLVMModuleRef,
+    pattern: &Pattern,
+) -> Vec<(LLVMValueRef, String)> {
+    definitions(module)
+        .into_iter()
+        .map(|(fnc, symbol, demangled)| (fnc, demangled.unwrap_or(symbol)))
+        .filter(|(_, name)| pattern.matches(name))
+        .collect()
+}
+
+/// Look up the single definition of a Rust path in the module.
+///
+/// # Safety
+///
+/// `module` must be a valid module.
+pub unsafe fn find_function(
+    module: LLVMModuleRef,
+    path: &str,
+) -> Result<LLVMValueRef, EnzymeBuildError> {
+    let definitions = definitions(module);
+    let matches: Vec<&(LLVMValueRef, String, Option<String>)> = definitions
+        .iter()
+        .filter(|(_, symbol, demangled)| symbol == path || demangled.as_deref() == Some(path))
+        .collect();
+    match matches.as_slice() {
+        [(fnc, _, _)] => Ok(*fnc),
+        [] => {
+            let paths: Vec<&str> = definitions
+                .iter()
+                .filter_map(|(_, _, demangled)| demangled.as_deref())
+                .collect();
+            Err(EnzymeBuildError::MissingFunction {
+                name: path.to_owned(),
+                near_matches: near_matches(path, &paths),
+            })
+        }
+        _ => Err(EnzymeBuildError::AmbiguousFunction {
+            name: path.to_owned(),
+            symbols: matches
+                .iter()
+                .map(|(_, symbol, _)| symbol.clone())
+                .collect(),
+        }),
+    }
+}
+
+/// The paths which the user most likely meant, best first.
+fn near_matches(path: &str, candidates: &[&str]) -> Vec<String> {
+    let last_segment = |path: &str| path.rsplit("::").next().unwrap_or(path).to_owned();
+    let wanted = last_segment(path);
+    let mut scored: Vec<(usize, usize, &str)> = candidates
+        .iter()
+        .map(|&candidate| {
+            let segment_distance = edit_distance(&wanted, &last_segment(candidate));
+            (segment_distance, edit_distance(path, candidate), candidate)
+        })
+        // Allow some typos in the function name, but the rest of the path may differ entirely.
+        .filter(|&(segment_distance, _, _)| segment_distance <= wanted.len() / 3)
+        .collect();
+    scored.sort();
+    let mut res: Vec<String> = scored.into_iter().map(|(_, _, c)| c.to_owned()).collect();
+    res.dedup();
+    res.truncate(MAX_NEAR_MATCHES);
+    res
+}
+
+/// Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
@@ -0,0 +1,162 @@
+use crate::{env_var, EnzymeBuildError};
+use llvm_sys::core::LLVMDisposeMessage;
+use llvm_sys::target_machine::{
+    LLVMGetHostCPUFeatures, LLVMGetHostCPUName, LLVMNormalizeTargetTriple,
+};
+use std::env;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+
+/// The machine which the gradient functions are compiled for.
+///
+/// By default everything is taken from cargo: the triple from `TARGET`, the cpu from
+/// `-C target-cpu` and the features from `CARGO_CFG_TARGET_FEATURE`.
+/// Only set them if you want something else than the rest of your crate.
+#[derive(Clone, Debug, Default)]
+pub struct TargetOptions {
+    triple: Option<String>,
+    cpu: Option<String>,
+    features: Option<String>,
+}
+
+/// The fully resolved TargetOptions, as passed to LLVM.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Target {
+    pub triple: CString,
+    pub cpu: CString,
+    pub features: CString,
+}
+
+impl TargetOptions {
+    pub fn new() -> TargetOptions {
+        TargetOptions::default()
+    }
+
+    /// A rust or LLVM target triple, e.g. `aarch64-unknown-linux-gnu`.
+    pub fn with_triple(mut self, triple: &str) -> TargetOptions {
+        self.triple = Some(triple.to_owned());
+        self
+    }
+
+    /// A cpu name as understood by `-C target-cpu`, e.g. `cortex-a72` or `native`.
+    pub fn with_cpu(mut self, cpu: &str) -> TargetOptions {
+        self.cpu = Some(cpu.to_owned());
+        self
+    }
+
+    /// LLVM features in the usual `+neon,-sve` form.
+    pub fn with_features(mut self, features: &str) -> TargetOptions {
+        self.features = Some(features.to_owned());
+        self
+    }
+
+    pub(crate) fn resolve(&self) -> Result<Target, EnzymeBuildError> {
+        let target = env_var("TARGET")?;
+        let host = env_var("HOST")?;
+        let triple = self.triple.clone().unwrap_or_else(|| target.clone());
+        let cross = triple != host;
+
+        let cpu = match self
+            .cpu
+            .clone()
+            .or_else(|| rustflags_codegen_opt("target-cpu"))
+        {
+            Some(cpu) if cpu == "native" => host_cpu(),
+            Some(cpu) => cpu,
+            // We used to build for the host cpu, so keep that for native builds.
+            None if !cross => host_cpu(),
+            None => "generic".to_owned(),
+        };
+
+        let features = match &self.features {
+            Some(features) => features.clone(),
+            None if self.triple.is_none() => match env::var("CARGO_CFG_TARGET_FEATURE") {
+                Ok(features) => llvm_features(&features),
+                Err(_) if !cross => host_features(),
+                Err(_) => String::new(),
+            },
+            // Cargo's features describe another target than the one we were asked for.
+            None => String::new(),
+        };
+
+        Ok(Target {
+            triple: normalize_triple(&triple)?,
+            cpu: c_option("target cpu", cpu)?,
+            features: c_option("target features", features)?,
+        })
+    }
+}
+
+/// The value of the last `-C <name>=..` which cargo passes to rustc.
+pub(crate) fn rustflags_codegen_opt(name: &str) -> Option<String> {
+    println!("cargo:rerun-if-env-changed=CARGO_ENCODED_RUSTFLAGS");
+    let flags = env::var("CARGO_ENCODED_RUSTFLAGS").ok()?;
+    let flags: Vec<&str> = flags.split('\x1f').collect();
+    let mut value = None;
+    for (i, flag) in flags.iter().enumerate() {
+        let codegen_opt = match *flag {
+            "-C" | "--codegen" => flags.get(i + 1).copied(),
+            _ => flag
+                .strip_prefix("-C")
+                .or_else(|| flag.strip_prefix("--codegen=")),
+        };
+        let option = codegen_opt.and_then(|x| x.split_once('='));
+        if let Some((key, val)) = option {
+            if key == name {
+                value = Some(val.to_owned());
+            }
+        }
+    }
+    value
+}
+
+/// Cargo lists the enabled features by their rust names, which don't always match LLVM.
+fn llvm_features(cargo_features: &str) -> String {
+    cargo_features
+        .split(',')
+        .filter(|feature| !feature.is_empty() && *feature != "crt-static")
+        .map(|feature| {
+            let feature = match feature {
+                "bmi1" => "bmi",
+                "cmpxchg16b" => "cx16",
+                "lahfsahf" => "sahf",
+                "pclmulqdq" => "pclmul",
+                "rdrand" => "rdrnd",
+                "fp" => "fp-armv8",
+                other => other,
+            };
+            "+".to_owned() + feature
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn c_option(option: &str, value: String) -> Result<CString, EnzymeBuildError> {
+    CString::new(value).map_err(|_| EnzymeBuildError::InvalidConfig {
+        option: option.to_owned(),
+        message: "It must not contain NUL bytes.".to_owned(),
+    })
+}
+
+fn normalize_triple(triple: &str) -> Result<CString, EnzymeBuildError> {
+    let c_triple = c_option("target triple", triple.to_owned())?;
+    Ok(unsafe { take_llvm_string(LLVMNormalizeTargetTriple(c_triple.as_ptr())) })
+}
+
+fn host_cpu() -> String {
+    unsafe { take_llvm_string(LLVMGetHostCPUName()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn host_features() -> String {
+    unsafe { take_llvm_string(LLVMGetHostCPUFeatures()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+unsafe fn take_llvm_string(msg: *mut c_char) -> CString {
+    let res = CStr::from_ptr(msg).to_owned();
+    LLVMDisposeMessage(msg);
+    res
+}
@@ -1,8 +1,9 @@
-use crate::enzyme::ReturnActivity;
-use crate::{get_type, FncInfo};
+use crate::enzyme::{DiffMode, ReturnActivity, CDIFFE_TYPE};
+use crate::{get_type, EnzymeBuildError, FncInfo};
 use llvm_sys::analysis::{LLVMVerifierFailureAction, LLVMVerifyFunction, LLVMVerifyModule};
 use llvm_sys::core::*;
 use llvm_sys::prelude::*;
+use llvm_sys::LLVMTypeKind;
 use std::ffi::CStr;
 use std::ptr;
 
@@ -16,11 +17,10 @@ unsafe fn verify_single(
 
     let num_parameters = LLVMCountParamTypes(fnc_type);
 
-    let mut parameter_types: Vec<LLVMTypeRef> = vec![];
-    parameter_types.reserve(num_parameters as usize);
+    let mut parameter_types: Vec<LLVMTypeRef> = Vec::with_capacity(num_parameters as usize);
     LLVMGetParamTypes(fnc_type, parameter_types.as_mut_ptr());
+    parameter_types.set_len(num_parameters as usize);
 
-    dbg!("First local check");
     // 1. Check that info.ret_info == None if fnc_type returns void
     if return_type == LLVMVoidTypeInContext(ctx) {
         if info.params.ret_info != ReturnActivity::None {
@@ -32,7 +32,6 @@ unsafe fn verify_single(
         return Err(error_msg);
     }
 
-    dbg!("Second local check");
     // 2. Check that we have one entry in input_activity for each parameter in fnc_type.params
     if num_parameters != info.params.input_activity.len() as u32 {
         let error_msg = format!("Your function has {} parameters, but you gave {} input activity values. Please provide exactly one per parameter!",
@@ -40,7 +39,104 @@ unsafe fn verify_single(
         return Err(error_msg);
     }
 
-    // 3. (optional) check for LLVMFloatType in params.
+    // 3. Forward mode has no output gradients, so Active arguments can't be handled
+    if info.params.mode == DiffMode::Forward
+        && info
+            .params
+            .input_activity
+            .contains(&CDIFFE_TYPE::DFT_OUT_DIFF)
+    {
+        let error_msg = "Forward mode doesn't support Active (DFT_OUT_DIFF) arguments. \
+                         Please use DFT_DUP_ARG and pass the tangent instead!"
+            .to_string();
+        return Err(error_msg);
+    }
+
+    // 4. Split mode needs a name for the augmented primal
+    if (info.params.mode == DiffMode::ReverseSplit) != info.aug_name.is_some() {
+        let error_msg = format!(
+            "{} needs a name for the augmented primal if and only if it uses split mode. \
+             Please create it with FncInfo::new_split!",
+            info.grad_name
+        );
+        return Err(error_msg);
+    }
+
+    // 5. Vector mode needs at least one lane
+    if info.params.width == 0 {
+        let error_msg = "The width of your FncInfo must be at least 1!".to_string();
+        return Err(error_msg);
+    }
+
+    // 6. Uncacheable flags are given for all parameters or for none
+    let num_uncacheable = info.params.uncacheable.len();
+    if num_uncacheable != 0 && num_uncacheable != num_parameters as usize {
+        let error_msg = format!("Your function has {} parameters, but you gave {} uncacheable flags. Please provide exactly one per parameter!",
+                                num_parameters, num_uncacheable);
+        return Err(error_msg);
+    }
+
+    // 7. Known values can only be given for existing integer parameters
+    for (i, values) in info.params.known_values.iter().enumerate() {
+        if values.is_empty() {
+            continue;
+        }
+        let is_int = parameter_types
+            .get(i)
+            .map(|&ty| LLVMGetTypeKind(ty) == LLVMTypeKind::LLVMIntegerTypeKind)
+            .unwrap_or(false);
+        if !is_int {
+            let error_msg = format!(
+                "You gave known values for parameter {}, but only integer parameters can have known values!",
+                i
+            );
+            return Err(error_msg);
+        }
+    }
+
+    // 8. Duplicated returns need a pointer to duplicate
+    let duplicated_ret = matches!(
+        info.params.ret_info,
+        ReturnActivity::Duplicated | ReturnActivity::DuplicatedNoNeed
+    );
+    if duplicated_ret && LLVMGetTypeKind(return_type) != LLVMTypeKind::LLVMPointerTypeKind {
+        let error_msg = "Your function isn't returning a pointer, so please don't set the ret_info of your FncInfo to Duplicated or DuplicatedNoNeed!".to_string();
+        return Err(error_msg);
+    }
+    // The combined reverse mode would only return the shadow after the reverse pass,
+    // when it is too late to seed it.
+    if duplicated_ret && info.params.mode == DiffMode::Reverse {
+        let error_msg = "Duplicated and DuplicatedNoNeed returns are only supported in forward mode and split mode. \
+                         Please create your FncInfo with FncInfo::new_fwd or FncInfo::new_split!"
+            .to_string();
+        return Err(error_msg);
+    }
+
+    // 9. In reverse mode only pointers can be duplicated, forward mode also accepts tangents of values
+    if info.params.mode != DiffMode::Forward {
+        for (i, (activity, &ty)) in info
+            .params
+            .input_activity
+            .iter()
+            .zip(parameter_types.iter())
+            .enumerate()
+        {
+            let duplicated = matches!(
+                activity,
+                CDIFFE_TYPE::DFT_DUP_ARG | CDIFFE_TYPE::DFT_DUP_NONEED
+            );
+            if duplicated && LLVMGetTypeKind(ty) != LLVMTypeKind::LLVMPointerTypeKind {
+                let error_msg = format!(
+                    "Parameter {} isn't a pointer, so it can't be DFT_DUP_ARG or DFT_DUP_NONEED. \
+                     Please use DFT_OUT_DIFF or DFT_CONSTANT instead!",
+                    i
+                );
+                return Err(error_msg);
+            }
+        }
+    }
+
+    // 10. (optional) check for LLVMFloatType in params.
 
     Ok(())
 }
@@ -49,50 +145,93 @@ pub fn verify_user_inputs(
     infos: Vec<FncInfo>,
     primary_functions: Vec<LLVMValueRef>,
     ctx: LLVMContextRef,
-) -> Result<(), String> {
-    dbg!("First global check");
+) -> Result<(), EnzymeBuildError> {
     if infos.len() != primary_functions.len() {
         let error_msg = format!(
-            "Number of primary functions and function informations differ. \
-            This should have been caught earlier, please report it! {} {}",
+            "Number of primary functions and function informations differ. {} {}",
             infos.len(),
             primary_functions.len()
         );
-        return Err(error_msg);
+        return Err(EnzymeBuildError::Internal(error_msg));
     }
 
     let mut grad_names = vec![];
     for info in &infos {
-        grad_names.push(info.grad_name.clone());
+        grad_names.append(&mut info.generated_names());
     }
     let mut unique_grad_names = grad_names.clone();
     unique_grad_names.sort();
     unique_grad_names.dedup();
-    dbg!("Second global check");
-    for i in 0..(unique_grad_names.len() - 1) {
-        if unique_grad_names[i] == unique_grad_names[i + 1] {
-            let error_msg = format!(
-                "You are assigning multiple gradient functions to {}. \
-                             Please double-check your build.rs file.",
-                unique_grad_names[i]
-            );
-            return Err(error_msg);
+    if unique_grad_names.len() != grad_names.len() {
+        for name in &unique_grad_names {
+            if grad_names.iter().filter(|&x| x == name).count() > 1 {
+                return Err(EnzymeBuildError::InvalidFncInfo {
+                    function: name.clone(),
+                    message: "You are assigning multiple gradient functions to it. \
+                              Please double-check your build.rs file."
+                        .to_string(),
+                });
+            }
         }
     }
 
-    dbg!("Moving to local checks: ", infos.len());
-    dbg!(infos[0].params.ret_info);
     for (info, &fnc) in infos.iter().zip(primary_functions.iter()) {
+        if fnc.is_null() {
+            // Higher-order derivative, will be checked once the primal got generated.
+            continue;
+        }
         unsafe {
             let fnc_type = LLVMTypeOf(fnc);
-            verify_single(info, fnc_type, ctx)?;
+            verify_single(info, fnc_type, ctx).map_err(|message| {
+                EnzymeBuildError::InvalidFncInfo {
+                    function: info.primary_name.clone(),
+                    message,
+                }
+            })?;
         }
     }
     Ok(())
 }
 
+/// Check a FncInfo whose primal is a previously generated function (higher-order derivatives).
+pub fn verify_generated_primal(
+    info: &FncInfo,
+    generated: LLVMValueRef,
+    ctx: LLVMContextRef,
+) -> Result<(), EnzymeBuildError> {
+    unsafe { verify_single(info, LLVMTypeOf(generated), ctx) }.map_err(|message| {
+        EnzymeBuildError::InvalidFncInfo {
+            function: info.primary_name.clone(),
+            message,
+        }
+    })
+}
+
+/// Sort the FncInfos such that every function which differentiates a generated function
+/// comes after the FncInfo generating it. The order of independent FncInfos is kept.
+pub fn order_by_dependency(infos: Vec<FncInfo>) -> Result<Vec<FncInfo>, EnzymeBuildError> {
+    let mut ordered: Vec<FncInfo> = vec![];
+    let mut pending = infos;
+    while !pending.is_empty() {
+        let generated_later: Vec<String> = pending
+            .iter()
+            .flat_map(|info| info.generated_names())
+            .collect();
+        let (ready, blocked): (Vec<FncInfo>, Vec<FncInfo>) = pending
+            .into_iter()
+            .partition(|info| !generated_later.contains(&info.primary_name));
+        if ready.is_empty() {
+            let names: Vec<String> = blocked.iter().map(|info| info.grad_name.clone()).collect();
+            return Err(EnzymeBuildError::DependencyCycle(names));
+        }
+        ordered.extend(ready);
+        pending = blocked;
+    }
+    Ok(ordered)
+}
+
 pub unsafe fn verify_function(fnc: LLVMValueRef) -> Result<(), String> {
-    let fnc_ok = LLVMVerifyFunction(fnc, LLVMVerifierFailureAction::LLVMAbortProcessAction) == 0;
+    let fnc_ok = LLVMVerifyFunction(fnc, LLVMVerifierFailureAction::LLVMReturnStatusAction) == 0;
     if fnc_ok {
         Ok(())
     } else {
@@ -108,10 +247,8 @@ pub unsafe fn verify_module(module: LLVMModuleRef) -> Result<(), String> {
         &mut msg,
     ) == 0;
     if !module_ok {
-        let c_msg = CStr::from_ptr(msg)
-            .to_str()
-            .expect("This msg should have been created by llvm!");
-        let error_msg = "Could not validate module!".to_owned() + c_msg;
+        let c_msg = CStr::from_ptr(msg).to_string_lossy();
+        let error_msg = "Could not validate module!".to_owned() + &c_msg;
         LLVMDisposeMessage(msg);
         return Err(error_msg);
     }
@@ -1,7 +1,8 @@
-use crate::get_type;
 use crate::verify::{compare_param_types, verify_function};
+use crate::{get_type, EnzymeBuildError};
 use llvm_sys::core::*;
 use llvm_sys::prelude::*;
+use llvm_sys::LLVMTypeKind;
 use std::ffi::CString;
 
 /// This function creates and returns a wrapper function 'fnc_name' around the given function.
@@ -25,33 +26,32 @@ pub unsafe fn move_return_into_args(
     fnc: LLVMValueRef,
     u_type: LLVMTypeRef,
     fnc_name: String,
-) -> LLVMValueRef {
+) -> Result<LLVMValueRef, EnzymeBuildError> {
     let f_type = LLVMTypeOf(fnc);
-    dbg!("Moving", fnc_name.clone());
-    dbg!("From: ", get_type(f_type), " into ", get_type(u_type));
 
     let inner_param_num = LLVMCountParams(fnc);
     let (outer_fnc, outer_bb, mut outer_args, inner_args, c_inner_fnc_name) =
-        create_wrapper(module, context, fnc, u_type, fnc_name);
+        create_wrapper(module, context, fnc, u_type, fnc_name.clone());
 
-    let _inner_ret_type = LLVMGetReturnType(LLVMGetElementType(f_type));
+    let inner_ret_type = LLVMGetReturnType(LLVMGetElementType(f_type));
     let outer_ret_type = LLVMGetReturnType(LLVMGetElementType(u_type));
     if outer_ret_type != LLVMVoidTypeInContext(context) {
-        let is = CString::from_raw(LLVMPrintTypeToString(outer_ret_type));
-        let should = CString::from_raw(LLVMPrintTypeToString(LLVMVoidType()));
-        panic!(
-            "Return struct isn't moved into args. Please report this. {} vs. {}",
-            is.to_str().unwrap(),
-            should.to_str().unwrap()
-        );
+        return Err(EnzymeBuildError::TypeMismatch {
+            function: fnc_name,
+            declared: get_type(outer_ret_type).to_string_lossy().into_owned(),
+            generated: "void".to_owned(),
+        });
     }
 
     if outer_args.len() != 1 + inner_param_num as usize {
-        panic!(
-            "Outer wrapper should have exactly one extra arg. Please report this. {} vs {}",
-            inner_param_num,
-            outer_args.len()
-        )
+        return Err(EnzymeBuildError::WrapperFailed {
+            function: fnc_name,
+            message: format!(
+                "Outer wrapper should have exactly one extra arg. {} vs {}",
+                inner_param_num,
+                outer_args.len()
+            ),
+        });
     }
 
     let mut input_args = outer_args.split_off(1);
@@ -63,7 +63,6 @@ pub unsafe fn move_return_into_args(
     // and use the name to look up it's actual definition, to compare it.
     //let out_type_name = LLVMGetStructName(out_extra_arg);
     if inner_ret_type != out_extra_arg {
-        dbg!(43);
         //let inner_ret = get_type(inner_ret_type);
         let inner_ret = get_type(inner_ret_type);
         let extra_arg = get_type(out_extra_arg);
@@ -73,43 +72,60 @@ pub unsafe fn move_return_into_args(
     }
     */
     if let Err(e) = compare_param_types(input_args.clone(), inner_args) {
-        panic!(
-            "Argument types differ between wrapper and wrapped function! {}",
-            e
-        );
+        return Err(EnzymeBuildError::WrapperFailed {
+            function: fnc_name,
+            message: format!(
+                "Argument types differ between wrapper and wrapped function! {}",
+                e
+            ),
+        });
     }
 
     let builder = LLVMCreateBuilderInContext(context);
     LLVMPositionBuilderAtEnd(builder, outer_bb);
-    outer_args[0] = LLVMBuildCall(
+    let struct_ret = LLVMBuildCall(
         builder,
         fnc,
         input_args.as_mut_ptr(),
         input_args.len() as u32,
         c_inner_fnc_name.as_ptr(),
     );
+    // The extra argument might point to a named struct, so we store through a pointer
+    // to the (layout compatible) struct which the wrapped function returns.
+    let ret_ptr_name = CString::new("ret_ptr").unwrap();
+    let ret_ptr = LLVMBuildBitCast(
+        builder,
+        outer_args[0],
+        LLVMPointerType(inner_ret_type, 0),
+        ret_ptr_name.as_ptr(),
+    );
+    let _store = LLVMBuildStore(builder, struct_ret, ret_ptr);
     let _ret = LLVMBuildRetVoid(builder);
     let _terminator = LLVMGetBasicBlockTerminator(outer_bb);
     //assert!(LLVMIsNull(terminator)!=0, "no terminator");
     LLVMDisposeBuilder(builder);
 
-    if let Err(e) = verify_function(outer_fnc) {
-        panic!("Creating a wrapper function failed! {}", e);
+    if let Err(message) = verify_function(outer_fnc) {
+        return Err(EnzymeBuildError::WrapperFailed {
+            function: fnc_name,
+            message,
+        });
     }
 
-    outer_fnc
+    Ok(outer_fnc)
 }
 
 /// This function creates and returns a wrapper function 'fnc_name' around the given function.
 ///
-/// The wrapped function is expected to return a struct `{ f64 }` consisting of exactly one `f64` value.
+/// The wrapped function is expected to return a struct `{ T }` consisting of exactly one value,
+/// e.g. a `f64` gradient or the shadow pointer of a duplicated return.
 /// The wrapper function will accept the same arguments as the wrapped function and return
-/// the inner `f64` instead of the struct.
+/// the inner value instead of the struct.
 ///
 /// # Safety
 ///
 /// The `module`, `context`, and `fnc` must all be valid.
-/// The function `fnc` must be part of the given module and return a struct with one f64
+/// The function `fnc` must be part of the given module and return a struct with one
 /// value and no other content.
 /// `u_type` and LLVMTypeOf(fnc) shall only differ by the return type, as specified above.
 pub unsafe fn extract_return_type(
@@ -118,24 +134,26 @@ pub unsafe fn extract_return_type(
     fnc: LLVMValueRef,
     u_type: LLVMTypeRef,
     fnc_name: String,
-) -> LLVMValueRef {
-    let f_type = LLVMTypeOf(fnc);
-    dbg!("Unpacking", fnc_name.clone());
-    dbg!("From: ", get_type(f_type), " into ", get_type(u_type));
-
+) -> Result<LLVMValueRef, EnzymeBuildError> {
     let inner_param_num = LLVMCountParams(fnc);
     let (outer_fnc, outer_bb, mut outer_args, inner_args, c_inner_fnc_name) =
-        create_wrapper(module, context, fnc, u_type, fnc_name);
+        create_wrapper(module, context, fnc, u_type, fnc_name.clone());
 
     if inner_param_num as usize != outer_args.len() {
-        panic!("Args len shouldn't differ. Please report this.");
+        return Err(EnzymeBuildError::WrapperFailed {
+            function: fnc_name,
+            message: "Args len shouldn't differ.".to_owned(),
+        });
     }
 
     if let Err(e) = compare_param_types(outer_args.clone(), inner_args) {
-        panic!(
-            "Argument types differ between wrapper and wrapped function! {}",
-            e
-        );
+        return Err(EnzymeBuildError::WrapperFailed {
+            function: fnc_name,
+            message: format!(
+                "Argument types differ between wrapper and wrapped function! {}",
+                e
+            ),
+        });
     }
 
     let builder = LLVMCreateBuilderInContext(context);
@@ -151,16 +169,225 @@ pub unsafe fn extract_return_type(
     let inner_grad_name = "foo".to_string();
     let c_inner_grad_name = CString::new(inner_grad_name).unwrap();
     let struct_ret = LLVMBuildExtractValue(builder, struct_ret, 0, c_inner_grad_name.as_ptr());
+    let outer_ret_type = LLVMGetReturnType(LLVMGetElementType(u_type));
+    let struct_ret = cast_value(builder, struct_ret, outer_ret_type);
     let _ret = LLVMBuildRet(builder, struct_ret);
     let _terminator = LLVMGetBasicBlockTerminator(outer_bb);
     //assert!(LLVMIsNull(terminator)!=0, "no terminator");
     LLVMDisposeBuilder(builder);
 
-    if let Err(e) = verify_function(outer_fnc) {
-        panic!("Creating a wrapper function failed! {}", e);
+    if let Err(message) = verify_function(outer_fnc) {
+        return Err(EnzymeBuildError::WrapperFailed {
+            function: fnc_name,
+            message,
+        });
+    }
+
+    Ok(outer_fnc)
+}
+
+/// This function creates and returns a wrapper function 'fnc_name' around the given function.
+///
+/// The wrapped function is expected to return a struct with the same number of elements as
+/// the struct returned by `u_type`, but with different element types. This happens for
+/// duplicated returns, where Enzyme returns `{ T*, T* }` while the C-Abi passes the
+/// primal and shadow pointer as integers. The wrapper will cast every element.
+///
+/// # Safety
+///
+/// The `module`, `context`, and `fnc` must all be valid.
+/// The function `fnc` must be part of the given module.
+/// `u_type` and LLVMTypeOf(fnc) shall only differ by the element types of the returned struct.
+pub unsafe fn cast_return_type(
+    module: LLVMModuleRef,
+    context: LLVMContextRef,
+    fnc: LLVMValueRef,
+    u_type: LLVMTypeRef,
+    fnc_name: String,
+) -> Result<LLVMValueRef, EnzymeBuildError> {
+    let inner_param_num = LLVMCountParams(fnc);
+    let (outer_fnc, outer_bb, mut outer_args, inner_args, c_inner_fnc_name) =
+        create_wrapper(module, context, fnc, u_type, fnc_name.clone());
+
+    if inner_param_num as usize != outer_args.len() {
+        return Err(EnzymeBuildError::WrapperFailed {
+            function: fnc_name,
+            message: "Args len shouldn't differ.".to_owned(),
+        });
+    }
+
+    if let Err(e) = compare_param_types(outer_args.clone(), inner_args) {
+        return Err(EnzymeBuildError::WrapperFailed {
+            function: fnc_name,
+            message: format!(
+                "Argument types differ between wrapper and wrapped function! {}",
+                e
+            ),
+        });
+    }
+
+    let outer_ret_type = LLVMGetReturnType(LLVMGetElementType(u_type));
+    let num_elements = LLVMCountStructElementTypes(outer_ret_type);
+    let mut element_types: Vec<LLVMTypeRef> = Vec::with_capacity(num_elements as usize);
+    LLVMGetStructElementTypes(outer_ret_type, element_types.as_mut_ptr());
+    element_types.set_len(num_elements as usize);
+
+    let builder = LLVMCreateBuilderInContext(context);
+    LLVMPositionBuilderAtEnd(builder, outer_bb);
+    let struct_ret = LLVMBuildCall(
+        builder,
+        fnc,
+        outer_args.as_mut_ptr(),
+        outer_args.len() as u32,
+        c_inner_fnc_name.as_ptr(),
+    );
+    let tmp_name = CString::new("elem").unwrap();
+    let mut outer_ret = LLVMGetUndef(outer_ret_type);
+    for (i, &element_type) in element_types.iter().enumerate() {
+        let element = LLVMBuildExtractValue(builder, struct_ret, i as u32, tmp_name.as_ptr());
+        let element = cast_value(builder, element, element_type);
+        outer_ret = LLVMBuildInsertValue(builder, outer_ret, element, i as u32, tmp_name.as_ptr());
+    }
+    let _ret = LLVMBuildRet(builder, outer_ret);
+    LLVMDisposeBuilder(builder);
+
+    if let Err(message) = verify_function(outer_fnc) {
+        return Err(EnzymeBuildError::WrapperFailed {
+            function: fnc_name,
+            message,
+        });
+    }
+
+    Ok(outer_fnc)
+}
+
+/// Convert `val` to `ty`, if both are of the same size but differ in their type,
+/// e.g. pointers passed as integers.
+unsafe fn cast_value(builder: LLVMBuilderRef, val: LLVMValueRef, ty: LLVMTypeRef) -> LLVMValueRef {
+    let val_type = LLVMTypeOf(val);
+    if val_type == ty {
+        return val;
+    }
+    let name = CString::new("cast").unwrap();
+    match (LLVMGetTypeKind(val_type), LLVMGetTypeKind(ty)) {
+        (LLVMTypeKind::LLVMPointerTypeKind, LLVMTypeKind::LLVMIntegerTypeKind) => {
+            LLVMBuildPtrToInt(builder, val, ty, name.as_ptr())
+        }
+        (LLVMTypeKind::LLVMIntegerTypeKind, LLVMTypeKind::LLVMPointerTypeKind) => {
+            LLVMBuildIntToPtr(builder, val, ty, name.as_ptr())
+        }
+        _ => LLVMBuildBitCast(builder, val, ty, name.as_ptr()),
+    }
+}
+
+/// Returns true if any parameter of `fnc` is an array, as generated by Enzyme's vector mode.
+///
+/// # Safety
+///
+/// `fnc` must be a valid function.
+pub unsafe fn has_vector_args(fnc: LLVMValueRef) -> bool {
+    get_params(fnc)
+        .iter()
+        .any(|&arg| LLVMGetTypeKind(LLVMTypeOf(arg)) == LLVMTypeKind::LLVMArrayTypeKind)
+}
+
+/// This function creates and returns a wrapper function 'fnc_name' around the given function.
+///
+/// The wrapped function is expected to take some of its (shadow) arguments as arrays `[N x T]`,
+/// as generated by Enzyme's vector mode. The wrapper function will instead accept N consecutive
+/// arguments of type `T` for each of them, pack them into the arrays and pass all arguments to
+/// the wrapped function. The return type is kept unchanged, so the result can be handled
+/// by the other wrappers afterwards.
+///
+/// # Safety
+///
+/// The `module`, `context`, and `fnc` must all be valid.
+/// The function `fnc` must be part of the given module.
+pub unsafe fn expand_vector_args(
+    module: LLVMModuleRef,
+    context: LLVMContextRef,
+    fnc: LLVMValueRef,
+    fnc_name: String,
+) -> Result<LLVMValueRef, EnzymeBuildError> {
+    let f_type = LLVMTypeOf(fnc);
+
+    let inner_param_types: Vec<LLVMTypeRef> =
+        get_params(fnc).iter().map(|&arg| LLVMTypeOf(arg)).collect();
+    let mut outer_param_types: Vec<LLVMTypeRef> = vec![];
+    for &param_type in &inner_param_types {
+        if LLVMGetTypeKind(param_type) == LLVMTypeKind::LLVMArrayTypeKind {
+            let lane_type = LLVMGetElementType(param_type);
+            for _ in 0..LLVMGetArrayLength(param_type) {
+                outer_param_types.push(lane_type);
+            }
+        } else {
+            outer_param_types.push(param_type);
+        }
+    }
+    let ret_type = LLVMGetReturnType(LLVMGetElementType(f_type));
+    let outer_fnc_type = LLVMFunctionType(
+        ret_type,
+        outer_param_types.as_mut_ptr(),
+        outer_param_types.len() as u32,
+        0,
+    );
+    let u_type = LLVMPointerType(outer_fnc_type, 0);
+
+    let (outer_fnc, outer_bb, outer_args, _inner_args, c_inner_fnc_name) =
+        create_wrapper(module, context, fnc, u_type, fnc_name.clone());
+
+    let builder = LLVMCreateBuilderInContext(context);
+    LLVMPositionBuilderAtEnd(builder, outer_bb);
+    let lane_name = CString::new("lanes").unwrap();
+    let mut outer_args = outer_args.into_iter();
+    let mut input_args: Vec<LLVMValueRef> = vec![];
+    for &param_type in &inner_param_types {
+        if LLVMGetTypeKind(param_type) == LLVMTypeKind::LLVMArrayTypeKind {
+            let mut lanes = LLVMGetUndef(param_type);
+            for lane in 0..LLVMGetArrayLength(param_type) {
+                let arg = outer_args
+                    .next()
+                    .ok_or_else(|| EnzymeBuildError::WrapperFailed {
+                        function: fnc_name.clone(),
+                        message: "Wrapper has fewer args than lanes.".to_owned(),
+                    })?;
+                lanes = LLVMBuildInsertValue(builder, lanes, arg, lane, lane_name.as_ptr());
+            }
+            input_args.push(lanes);
+        } else {
+            input_args.push(
+                outer_args
+                    .next()
+                    .ok_or_else(|| EnzymeBuildError::WrapperFailed {
+                        function: fnc_name.clone(),
+                        message: "Wrapper has fewer args than wrapped function.".to_owned(),
+                    })?,
+            );
+        }
+    }
+
+    let ret = LLVMBuildCall(
+        builder,
+        fnc,
+        input_args.as_mut_ptr(),
+        input_args.len() as u32,
+        c_inner_fnc_name.as_ptr(),
+    );
+    if ret_type == LLVMVoidTypeInContext(context) {
+        let _ret = LLVMBuildRetVoid(builder);
+    } else {
+        let _ret = LLVMBuildRet(builder, ret);
+    }
+    LLVMDisposeBuilder(builder);
+
+    if let Err(message) = verify_function(outer_fnc) {
+        return Err(EnzymeBuildError::WrapperFailed {
+            function: fnc_name,
+            message,
+        });
     }
 
-    outer_fnc
+    Ok(outer_fnc)
 }
 
 unsafe fn create_wrapper(
@@ -207,7 +434,7 @@ unsafe fn create_wrapper(
     )
 }
 
-fn get_params(fnc: LLVMValueRef) -> Vec<LLVMValueRef> {
+pub fn get_params(fnc: LLVMValueRef) -> Vec<LLVMValueRef> {
     unsafe {
         let param_num = LLVMCountParams(fnc) as usize;
         let mut fnc_args: Vec<LLVMValueRef> = vec![];